@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use neotwitch::{CommandInvocation, CommandSpec, IrcMessage};
+use tokio::time::Instant;
+
+use crate::Address;
+
+struct Command {
+    spec: CommandSpec,
+    // The client to forward invocations to, `None` for commands from the config
+    owner: Option<Address>,
+}
+
+// -----------------------------------------------------------------------------
+//     - Commands -
+//     Chat commands like `!hello`, with cooldowns and permissions
+// -----------------------------------------------------------------------------
+pub struct Commands {
+    prefix: String,
+    commands: HashMap<String, Command>,
+    // Last use of a command in a channel: (channel, name)
+    last_used: HashMap<(String, String), Instant>,
+    // Last use of a command by a user in a channel: (channel, name, login)
+    last_used_by: HashMap<(String, String, String), Instant>,
+}
+
+impl Commands {
+    pub fn new(prefix: &str) -> Self {
+        Self {
+            prefix: prefix.into(),
+            commands: HashMap::new(),
+            last_used: HashMap::new(),
+            last_used_by: HashMap::new(),
+        }
+    }
+
+    /// Add a command.
+    /// Fails if a command with the same name already exists.
+    pub fn register(&mut self, mut spec: CommandSpec, owner: Option<Address>) -> Result<(), String> {
+        spec.name = spec.name.trim_start_matches(self.prefix.as_str()).to_lowercase();
+        if spec.name.is_empty() || spec.name.contains(char::is_whitespace) {
+            return Err(format!("Invalid command name \"{}\"", spec.name));
+        }
+
+        if self.commands.contains_key(&spec.name) {
+            return Err(format!("Command {}{} already exists", self.prefix, spec.name));
+        }
+
+        self.commands.insert(spec.name.clone(), Command { spec, owner });
+        Ok(())
+    }
+
+    /// Remove all the commands registered by a client
+    pub fn remove_owner(&mut self, owner: Address) {
+        self.commands.retain(|_, command| command.owner != Some(owner));
+    }
+
+    /// Find the command in a message, if the user is allowed to use it and it's not on cooldown.
+    /// Returns the invocation, the response template and the client to forward it to.
+    /// Messages from other channels in a Shared Chat session are ignored,
+    /// as the command is for the channel it was sent in.
+    pub fn invoke(
+        &mut self,
+        msg: &IrcMessage,
+        now: Instant,
+    ) -> Option<(CommandInvocation, Option<String>, Option<Address>)> {
+        if msg.is_from_other_room() {
+            return None;
+        }
+
+        let invocation = CommandInvocation::parse(msg, &self.prefix)?;
+        let command = self.commands.get(&invocation.name)?;
+
+        if invocation.permission < command.spec.permission {
+            return None;
+        }
+
+        let key = (invocation.channel.clone(), invocation.name.clone());
+        let user_key = (key.0.clone(), key.1.clone(), invocation.login.clone());
+
+        let on_cooldown = |last: Option<&Instant>, seconds: u64| match last {
+            Some(last) => now.duration_since(*last) < Duration::from_secs(seconds),
+            None => false,
+        };
+
+        if on_cooldown(self.last_used.get(&key), command.spec.cooldown)
+            || on_cooldown(self.last_used_by.get(&user_key), command.spec.user_cooldown)
+        {
+            return None;
+        }
+
+        let response = command.spec.response.clone();
+        let owner = command.owner;
+        self.last_used.insert(key, now);
+        self.last_used_by.insert(user_key, now);
+
+        // Forget the users who are past the longest cooldown, or this grows with every user
+        let longest = self.commands.values().map(|c| c.spec.user_cooldown).max().unwrap_or(0);
+        self.last_used_by.retain(|_, last| now.duration_since(*last) < Duration::from_secs(longest));
+
+        Some((invocation, response, owner))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use neotwitch::{Permission, SharedChatSource};
+
+    use super::*;
+
+    fn msg(login: &str, badges: &str, message: &str) -> IrcMessage {
+        let tags = HashMap::from([("badges".to_string(), badges.to_string())]);
+        IrcMessage::new(login.into(), "togglebit".into(), message.into(), false, tags)
+    }
+
+    fn spec(name: &str, permission: Permission, cooldown: u64, user_cooldown: u64) -> CommandSpec {
+        CommandSpec { name: name.into(), permission, cooldown, user_cooldown, response: Some("hi {user}".into()) }
+    }
+
+    fn commands(spec: CommandSpec) -> Commands {
+        let mut commands = Commands::new("!");
+        commands.register(spec, None).unwrap();
+        commands
+    }
+
+    #[test]
+    fn permissions() {
+        let mut commands = commands(spec("so", Permission::Moderator, 0, 0));
+        let now = Instant::now();
+
+        assert!(commands.invoke(&msg("randomuser", "subscriber/12", "!so togglebit"), now).is_none());
+        assert!(commands.invoke(&msg("randomuser", "moderator/1", "!so togglebit"), now).is_some());
+
+        let (invocation, response, owner) = commands.invoke(&msg("togglebit", "broadcaster/1", "!SO a b"), now).unwrap();
+        assert_eq!(invocation.name, "so");
+        assert_eq!(invocation.args, vec!["a", "b"]);
+        assert_eq!(invocation.permission, Permission::Broadcaster);
+        assert_eq!(response.as_deref(), Some("hi {user}"));
+        assert_eq!(owner, None);
+
+        assert!(commands.invoke(&msg("randomuser", "", "so togglebit"), now).is_none());
+        assert!(commands.invoke(&msg("randomuser", "", "!nope"), now).is_none());
+    }
+
+    #[test]
+    fn cooldowns() {
+        let mut commands = commands(spec("hello", Permission::Everyone, 5, 30));
+        let now = Instant::now();
+
+        assert!(commands.invoke(&msg("a", "", "!hello"), now).is_some());
+        // Global cooldown
+        assert!(commands.invoke(&msg("b", "", "!hello"), now + Duration::from_secs(1)).is_none());
+        assert!(commands.invoke(&msg("b", "", "!hello"), now + Duration::from_secs(5)).is_some());
+        // User cooldown
+        assert!(commands.invoke(&msg("a", "", "!hello"), now + Duration::from_secs(10)).is_none());
+        assert!(commands.invoke(&msg("a", "", "!hello"), now + Duration::from_secs(30)).is_some());
+
+        // Users past the cooldown are forgotten
+        assert!(commands.invoke(&msg("c", "", "!hello"), now + Duration::from_secs(61)).is_some());
+        assert_eq!(commands.last_used_by.len(), 1);
+    }
+
+    #[test]
+    fn shared_chat() {
+        let mut commands = commands(spec("hello", Permission::Everyone, 0, 0));
+        let mut msg = msg("a", "", "!hello");
+        msg.tags.insert("room-id".into(), "474725923".into());
+        msg.source = Some(SharedChatSource {
+            room_id: "12345".into(),
+            message_id: "0136c224-4c6d-4554-b5ad-17b6a70ad96d".into(),
+            badges: Vec::new(),
+            badge_info: Vec::new(),
+        });
+        assert!(commands.invoke(&msg, Instant::now()).is_none());
+
+        // Sent in this channel during the session
+        msg.source.as_mut().unwrap().room_id = "474725923".into();
+        assert!(commands.invoke(&msg, Instant::now()).is_some());
+    }
+
+    #[test]
+    fn client_commands() {
+        let mut commands = commands(spec("hello", Permission::Everyone, 0, 0));
+        let client = Address::Connection(1);
+
+        assert!(commands.register(spec("!hello", Permission::Everyone, 0, 0), Some(client)).is_err());
+        assert!(commands.register(spec("!dice", Permission::Everyone, 0, 0), Some(client)).is_ok());
+
+        let (_, _, owner) = commands.invoke(&msg("a", "", "!dice"), Instant::now()).unwrap();
+        assert_eq!(owner, Some(client));
+
+        commands.remove_owner(client);
+        assert!(commands.invoke(&msg("a", "", "!dice"), Instant::now()).is_none());
+    }
+}
@@ -1,14 +1,29 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use log::{error, info};
 use std::time::Duration;
 use tinyroute::{Agent, Message, ToAddress};
-use tokio::time;
+use tokio::time::{self, Instant};
 
 use super::twitch::{connect_chat, Sink, SinkExt, Stream, StreamExt, WsMessage};
 use super::Address;
-use crate::config::Config;
+use crate::config::{validate_channel, Config};
+use neotwitch::{render, ChatResponse, CommandEvent, CommandSpec, Irc, IrcMessage, Notice, NoticeKind, Say};
+use commands::Commands;
+use outbound::{ClientCommand, Outbound, Queued};
+use state::ChatState;
 
+mod commands;
+mod outbound;
 mod parse;
+mod state;
+
+// Twitch doesn't require the client to ping, but it's the only way
+// to notice a connection that is no longer alive.
+const PING_INTERVAL: Duration = Duration::from_secs(60);
+const PONG_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Twitch drops messages longer than this (in characters)
+const MAX_MESSAGE_LEN: usize = 500;
 
 // -----------------------------------------------------------------------------
 //     - Irc Sink -
@@ -27,23 +42,171 @@ async fn connect_irc(config: &Config) -> Result<(IrcWriter, Stream)> {
     let (sink, stream) = connect_chat().await?;
     let mut sink = IrcWriter(sink);
 
-    sink.send(format!("PASS oauth:{}\r\n", config.token)).await?;
+    // Anonymous users only need a `justinfan` nick
+    match config.anonymous {
+        true => info!("No credentials, reading chat anonymously as {}", config.nick),
+        false => sink.send(format!("PASS oauth:{}\r\n", config.token)).await?,
+    }
     sink.send(format!("NICK {}\r\n", config.nick)).await?;
-    sink.send(format!("CAP REQ :twitch.tv/tags twitch.tv/commands\r\n")).await?;
-
-    for channel in &config.irc_channels {
-        sink.send(format!("JOIN {}\r\n", channel)).await?;
-        info!("Joined {}", channel);
+    match config.membership {
+        true => sink.send("CAP REQ :twitch.tv/tags twitch.tv/commands twitch.tv/membership\r\n").await?,
+        false => sink.send("CAP REQ :twitch.tv/tags twitch.tv/commands\r\n").await?,
     }
 
     Ok((sink, stream))
 }
 
+// Raw messages are only sent to the subscribers who asked for them
+fn recipients<'a>(msg: &Irc, subscribers: &'a [Address], raw_subscribers: &'a [Address]) -> &'a [Address] {
+    match msg {
+        Irc::Raw { .. } => raw_subscribers,
+        _ => subscribers,
+    }
+}
+
+// The channel of a `join` or `part` command, e.g `join #togglebit`
+fn parse_channel(bytes: &[u8]) -> Result<String, String> {
+    let channel = std::str::from_utf8(bytes).map_err(|_| "Channel name is not valid utf-8".to_string())?.trim();
+    match validate_channel(channel) {
+        Some(err) => Err(err),
+        None => Ok(channel.to_lowercase()),
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Say -
+// -----------------------------------------------------------------------------
+// Parse and check a `say` command before it's sent to Twitch
+fn parse_say(bytes: &[u8]) -> Result<Say, String> {
+    let mut say = serde_json::from_slice::<Say>(bytes).map_err(|e| format!("Invalid say command: {}", e))?;
+    say.channel = say.channel.trim_start_matches('#').to_lowercase();
+
+    if let Some(err) = validate_channel(&format!("#{}", say.channel)) {
+        return Err(err);
+    }
+
+    match validate_text(&say.text) {
+        Some(err) => Err(err),
+        None => Ok(say),
+    }
+}
+
+fn validate_text(text: &str) -> Option<String> {
+    if text.trim().is_empty() {
+        return Some("Text can not be empty".into());
+    }
+
+    // A line break would end the PRIVMSG and send the rest as another command
+    if text.contains(['\r', '\n']) {
+        return Some("Text can not contain line breaks".into());
+    }
+
+    if text.chars().count() > MAX_MESSAGE_LEN {
+        return Some(format!("Text can not be longer than {} characters", MAX_MESSAGE_LEN));
+    }
+
+    None
+}
+
+// -----------------------------------------------------------------------------
+//     - Commands -
+// -----------------------------------------------------------------------------
+// Reply to a chat command, and pass it on to the client that registered it
+async fn invoke_command(
+    msg: &IrcMessage,
+    commands: &mut Commands,
+    outbound: &mut Outbound,
+    agent: &Agent<(), Address>,
+    config: &Config,
+) -> Result<()> {
+    let (invocation, reply, owner) = match commands.invoke(msg, Instant::now()) {
+        Some(invoked) => invoked,
+        None => return Ok(()),
+    };
+
+    // Can't reply without a token
+    if let (Some(template), false) = (reply, config.anonymous) {
+        let text = render(&template, &invocation);
+        match validate_text(&text) {
+            Some(err) => error!("Can't reply to {}: {}", invocation.name, err),
+            None => {
+                let say = Say { channel: invocation.channel.clone(), text, reply_to: None, id: None };
+                let line = say.to_irc_line();
+                let queued = Queued { sender: None, command: ClientCommand::Command, channel: say.channel.clone(), id: None, line };
+                // Under Drop the one left out can be a client's say, which is still owed an answer
+                if let Err(overflow) = outbound.push_message(queued) {
+                    let reason = format!("Outbound queue for #{} is full", overflow.channel);
+                    if overflow.sender.is_none() {
+                        error!("{}", reason);
+                    }
+                    let error = response(overflow.command, Some(&overflow.channel), overflow.id, Err(reason));
+                    respond(agent, overflow.sender, &error).await?;
+                }
+            }
+        }
+    }
+
+    if let Some(owner) = owner {
+        let bytes = serde_json::to_vec(&CommandEvent::Invoked(invocation))?;
+        agent.send_remote([owner], &bytes).await?;
+    }
+
+    Ok(())
+}
+
+// Send everything the rate limits allow right now.
+// An error means the connection to Twitch is no good.
+async fn flush(outbound: &mut Outbound, sink: &mut IrcWriter, agent: &Agent<(), Address>) -> Result<()> {
+    while let Some(queued) = outbound.pop(Instant::now()) {
+        info!("< {:?}", queued.line);
+
+        let result = sink.send(queued.line).await;
+        let ack = match &result {
+            Ok(()) => Ok(()),
+            Err(e) => Err(e.to_string()),
+        };
+        respond(agent, queued.sender, &response(queued.command, Some(&queued.channel), queued.id, ack)).await?;
+        result?;
+    }
+
+    Ok(())
+}
+
+// The response to a command, along with the channel and the id the client sent, if any
+fn response(command: ClientCommand, channel: Option<&str>, id: Option<String>, result: Result<(), String>) -> ChatResponse {
+    let command = command.as_str().into();
+    let channel = channel.map(|c| c.trim_start_matches('#').to_lowercase());
+    match result {
+        Ok(()) => ChatResponse::Ok { command, channel, id },
+        Err(reason) => ChatResponse::Error { command, channel, id, reason },
+    }
+}
+
+async fn respond(agent: &Agent<(), Address>, recipient: Option<Address>, response: &ChatResponse) -> Result<()> {
+    if let Some(recipient) = recipient {
+        let bytes = serde_json::to_vec(response)?;
+        agent.send_remote([recipient], &bytes).await?;
+    }
+    Ok(())
+}
+
 // -----------------------------------------------------------------------------
 //     - Run -
 // -----------------------------------------------------------------------------
 pub async fn run(mut agent: Agent<(), Address>, config: &crate::config::Config) -> Result<()> {
     let mut subscribers: Vec<Address> = Vec::new();
+    let mut raw_subscribers: Vec<Address> = Vec::new();
+    let mut chat_state = ChatState::default();
+    let mut rejected_lines = 0usize;
+    let mut outbound = Outbound::new(config.overflow_policy, config.queue_size);
+    let mut commands = Commands::new(&config.command_prefix);
+    for spec in &config.commands {
+        if let Err(e) = commands.register(spec.clone(), None) {
+            error!("Invalid command in config: {}", e);
+        }
+    }
+    // Joined channels, re-joined after every reconnect
+    let mut channels = config.irc_channels.iter().map(|c| c.to_lowercase()).collect::<Vec<_>>();
 
     let mut reconnect_count = 0;
 
@@ -57,6 +220,24 @@ pub async fn run(mut agent: Agent<(), Address>, config: &crate::config::Config)
         let (mut sink, mut stream) = match connect_irc(config).await {
             Ok(s) => {
                 reconnect_count = 0;
+                chat_state.clear_chatters();
+                let mut pending = outbound.take_joins();
+                for channel in &channels {
+                    // A client's JOIN that never went out keeps its sender, so it is still answered
+                    let index = pending.iter().position(|q| q.command == ClientCommand::Join && q.channel == *channel);
+                    let queued = match index {
+                        Some(index) => pending.remove(index),
+                        None => {
+                            let line = format!("JOIN {}\r\n", channel);
+                            Queued { sender: None, command: ClientCommand::Join, channel: channel.clone(), id: None, line }
+                        }
+                    };
+                    outbound.push_membership(queued);
+                }
+                // What's left are PARTs, and a new connection hasn't joined those channels
+                for queued in pending {
+                    respond(&agent, queued.sender, &response(queued.command, Some(&queued.channel), queued.id, Ok(()))).await?;
+                }
                 s
             }
             Err(_) => {
@@ -66,8 +247,29 @@ pub async fn run(mut agent: Agent<(), Address>, config: &crate::config::Config)
             }
         };
 
-        loop {
+        let mut ping_interval = time::interval(PING_INTERVAL);
+        ping_interval.tick().await; // The first tick is instant
+        let mut pong_deadline: Option<Instant> = None;
+
+        'read: loop {
             tokio::select! {
+                _ = ping_interval.tick() => {
+                    if sink.send("PING :tmi.twitch.tv\r\n").await.is_err() {
+                        error!("Failed to ping");
+                        break 'read; // cause a reconnect
+                    }
+                    pong_deadline.get_or_insert_with(|| Instant::now() + PONG_TIMEOUT);
+                }
+                _ = time::sleep_until(pong_deadline.unwrap_or_else(Instant::now)), if pong_deadline.is_some() => {
+                    error!("No pong from Twitch IRC in {} seconds", PONG_TIMEOUT.as_secs());
+                    break 'read; // cause a reconnect
+                }
+                _ = time::sleep_until(outbound.next_ready(Instant::now()).unwrap_or_else(Instant::now)), if outbound.has_pending() => {
+                    if let Err(e) = flush(&mut outbound, &mut sink, &agent).await {
+                        error!("Failed to send to Twitch IRC: {}", e);
+                        break 'read; // cause a reconnect
+                    }
+                }
                 chat_msg = stream.next() => {
                     match chat_msg {
                         None => {
@@ -78,22 +280,77 @@ pub async fn run(mut agent: Agent<(), Address>, config: &crate::config::Config)
                             error!("Failed to receive Twitch chat mesasge: {}", e);
                             break; // cause a reconnect
                         }
-                        Some(Ok(WsMessage::Text(msg))) => {
-                            info!("{:?}", msg);
+                        Some(Ok(WsMessage::Text(frame))) => {
+                            // Twitch can send multiple lines in a single frame
+                            for msg in parse::lines(&frame) {
+                                info!("{:?}", msg);
 
-                            if msg.starts_with("PING") {
-                                info!("> Ping");
-                                if let Err(_) = sink.send("PONG".to_string()).await {
-                                    error!("Failed to pong");
-                                    break; // cause a reconnect
+                                // Echo the payload, e.g `PING :tmi.twitch.tv` -> `PONG :tmi.twitch.tv`
+                                if let Some(payload) = msg.strip_prefix("PING") {
+                                    info!("> Ping");
+                                    if sink.send(format!("PONG{}\r\n", payload.trim_end())).await.is_err() {
+                                        error!("Failed to pong");
+                                        break 'read; // cause a reconnect
+                                    }
+                                    info!("< Pong");
+                                    continue;
                                 }
-                                info!("< Pong");
-                                continue;
-                            }
 
-                            if let Some(msg) = parse::parse(&msg) {
+                                // Reply to our own ping
+                                if msg.starts_with(":tmi.twitch.tv PONG") {
+                                    pong_deadline = None;
+                                    continue;
+                                }
+
+                                let msg = match parse::parse(msg) {
+                                    Ok(msg) => msg,
+                                    Err(e) => {
+                                        rejected_lines += 1;
+                                        error!("Rejected IRC line ({} in total): {}", rejected_lines, e);
+                                        continue;
+                                    }
+                                };
+
+                                // Reconnecting with a bad token won't help
+                                if let Irc::Notice(Notice { kind: NoticeKind::LoginFailed, message, .. }) = &msg {
+                                    error!("Twitch IRC login failed: {}", message);
+                                    agent.shutdown_router().await;
+                                    return Err(anyhow!("Twitch IRC login failed: {}", message));
+                                }
+
+                                // Before the dedupe, as the copy kept could be the other room's, which commands ignore
+                                if let Irc::Message(irc_msg) = &msg {
+                                    invoke_command(irc_msg, &mut commands, &mut outbound, &agent, config).await?;
+                                }
+
+                                if config.dedupe_shared_chat && chat_state.is_shared_duplicate(&msg) {
+                                    continue;
+                                }
+
+                                // Stop re-joining a channel that can't be joined
+                                if let Irc::Notice(Notice { channel: Some(channel), kind, .. }) = &msg {
+                                    if kind.is_join_failure() {
+                                        info!("Could not join #{}", channel);
+                                        channels.retain(|c| c.trim_start_matches('#') != channel);
+                                        chat_state.remove_channel(channel);
+                                    }
+                                }
+
+                                if let Irc::UserState(state) = &msg {
+                                    if let Some(channel) = &state.channel {
+                                        outbound.set_elevated(channel, state.moderator || state.is_broadcaster());
+                                    }
+                                }
+
+                                let msg = chat_state.update(msg);
                                 let bytes = serde_json::to_vec(&msg).unwrap();
-                                agent.send_remote(subscribers.iter().copied(), &bytes).await?;
+                                let recipients = recipients(&msg, &subscribers, &raw_subscribers);
+                                agent.send_remote(recipients.iter().copied(), &bytes).await?;
+
+                                if let Irc::Reconnect = msg {
+                                    info!("Twitch IRC asked for a reconnect");
+                                    break 'read;
+                                }
                             }
                         }
                         Some(_) => {} // unsupported message
@@ -115,11 +372,154 @@ pub async fn run(mut agent: Agent<(), Address>, config: &crate::config::Config)
                                         agent.track(sender).await?;
                                     }
                                 }
-                                // If it's nor shutdown or sub then it's probably some test data
+                                b"sub-raw" => {
+                                    if !subscribers.contains(&sender) {
+                                        subscribers.push(sender);
+                                        agent.track(sender).await?;
+                                    }
+                                    if !raw_subscribers.contains(&sender) {
+                                        info!("{} subscribed to raw irc", sender.to_string());
+                                        raw_subscribers.push(sender);
+                                    }
+                                }
+                                b"userstate" => {
+                                    for state in chat_state.user_states() {
+                                        let bytes = serde_json::to_vec(&state)?;
+                                        agent.send_remote([sender], &bytes).await?;
+                                    }
+                                }
+                                b"chatters" => {
+                                    for chatters in chat_state.chatters() {
+                                        let bytes = serde_json::to_vec(&chatters)?;
+                                        agent.send_remote([sender], &bytes).await?;
+                                    }
+                                }
+                                b"roomstate" => {
+                                    for state in chat_state.room_states() {
+                                        let bytes = serde_json::to_vec(&Irc::RoomState(state.clone()))?;
+                                        agent.send_remote([sender], &bytes).await?;
+                                    }
+                                }
+                                bytes if bytes.starts_with(b"say ") => {
+                                    if config.anonymous {
+                                        let reason = "Read-only: can not send messages without a token".to_string();
+                                        respond(&agent, Some(sender), &response(ClientCommand::Say, None, None, Err(reason))).await?;
+                                        continue;
+                                    }
+
+                                    let say = match parse_say(&bytes[4..]) {
+                                        Ok(say) => say,
+                                        Err(reason) => {
+                                            error!("{} failed to say: {}", sender.to_string(), reason);
+                                            respond(&agent, Some(sender), &response(ClientCommand::Say, None, None, Err(reason))).await?;
+                                            continue;
+                                        }
+                                    };
+
+                                    let channel = format!("#{}", say.channel);
+                                    if !channels.contains(&channel) {
+                                        let reason = format!("Not joined to {}", channel);
+                                        respond(&agent, Some(sender), &response(ClientCommand::Say, Some(&channel), say.id, Err(reason))).await?;
+                                        continue;
+                                    }
+
+                                    // The sender is acknowledged once the message is sent
+                                    let line = say.to_irc_line();
+                                    let queued = Queued { sender: Some(sender), command: ClientCommand::Say, channel: say.channel, id: say.id, line };
+                                    if let Err(overflow) = outbound.push_message(queued) {
+                                        let reason = format!("Outbound queue for #{} is full", overflow.channel);
+                                        let response = response(overflow.command, Some(&overflow.channel), overflow.id, Err(reason));
+                                        respond(&agent, overflow.sender, &response).await?;
+                                    }
+
+                                    if let Err(e) = flush(&mut outbound, &mut sink, &agent).await {
+                                        error!("Failed to send to Twitch IRC: {}", e);
+                                        break 'read; // cause a reconnect
+                                    }
+                                }
+                                bytes if bytes.starts_with(b"join ") => {
+                                    let channel = match parse_channel(&bytes[5..]) {
+                                        Ok(channel) => channel,
+                                        Err(reason) => {
+                                            respond(&agent, Some(sender), &response(ClientCommand::Join, None, None, Err(reason))).await?;
+                                            continue;
+                                        }
+                                    };
+
+                                    if channels.contains(&channel) {
+                                        respond(&agent, Some(sender), &response(ClientCommand::Join, Some(&channel), None, Ok(()))).await?;
+                                        continue;
+                                    }
+
+                                    info!("{} joined {}", sender.to_string(), channel);
+                                    let line = format!("JOIN {}\r\n", channel);
+                                    outbound.push_membership(Queued { sender: Some(sender), command: ClientCommand::Join, channel: channel.clone(), id: None, line });
+                                    channels.push(channel);
+
+                                    if let Err(e) = flush(&mut outbound, &mut sink, &agent).await {
+                                        error!("Failed to send to Twitch IRC: {}", e);
+                                        break 'read; // cause a reconnect
+                                    }
+                                }
+                                bytes if bytes.starts_with(b"part ") => {
+                                    let result = parse_channel(&bytes[5..]).and_then(|channel| {
+                                        match channels.iter().position(|c| c == &channel) {
+                                            Some(index) => Ok(channels.remove(index)),
+                                            None => Err(format!("Not joined to {}", channel)),
+                                        }
+                                    });
+
+                                    let channel = match result {
+                                        Ok(channel) => channel,
+                                        Err(reason) => {
+                                            respond(&agent, Some(sender), &response(ClientCommand::Part, None, None, Err(reason))).await?;
+                                            continue;
+                                        }
+                                    };
+
+                                    info!("{} parted {}", sender.to_string(), channel);
+                                    chat_state.remove_channel(channel.trim_start_matches('#'));
+
+                                    // Nothing to part if the JOIN never went out
+                                    if let Some(join) = outbound.cancel_join(&channel) {
+                                        let reason = format!("Parted {} before it was joined", channel);
+                                        respond(&agent, join.sender, &response(ClientCommand::Join, Some(&channel), None, Err(reason))).await?;
+                                        respond(&agent, Some(sender), &response(ClientCommand::Part, Some(&channel), None, Ok(()))).await?;
+                                        continue;
+                                    }
+
+                                    let line = format!("PART {}\r\n", channel);
+                                    outbound.push_membership(Queued { sender: Some(sender), command: ClientCommand::Part, channel, id: None, line });
+
+                                    if let Err(e) = flush(&mut outbound, &mut sink, &agent).await {
+                                        error!("Failed to send to Twitch IRC: {}", e);
+                                        break 'read; // cause a reconnect
+                                    }
+                                }
+                                bytes if bytes.starts_with(b"command ") => {
+                                    let result = serde_json::from_slice::<CommandSpec>(&bytes[8..])
+                                        .map_err(|e| format!("Invalid command: {}", e))
+                                        .and_then(|spec| commands.register(spec, Some(sender)));
+
+                                    if result.is_ok() {
+                                        agent.track(sender).await?;
+                                    }
+                                    respond(&agent, Some(sender), &response(ClientCommand::Command, None, None, result)).await?;
+                                }
+                                // If it's not a command then it's probably some test data
                                 bytes => {
-                                    if let Ok(Some(irc_msg)) = std::str::from_utf8(&bytes).map(parse::parse) {
+                                    let frame = std::str::from_utf8(&bytes).unwrap_or_default();
+                                    for line in parse::lines(frame) {
+                                        let irc_msg = match parse::parse(line) {
+                                            Ok(msg) => chat_state.update(msg),
+                                            Err(e) => {
+                                                info!("Invalid test data: {}", e);
+                                                continue;
+                                            }
+                                        };
                                         if let Ok(serialized_message) = serde_json::to_vec(&irc_msg) {
-                                            agent.send_remote(subscribers.iter().copied(), &serialized_message).await?;
+                                            let recipients = recipients(&irc_msg, &subscribers, &raw_subscribers);
+                                            agent.send_remote(recipients.iter().copied(), &serialized_message).await?;
                                         }
                                     }
                                 }
@@ -129,6 +529,8 @@ pub async fn run(mut agent: Agent<(), Address>, config: &crate::config::Config)
                         Message::AgentRemoved(sender) => {
                             info!("{} unsubscribed from irc", sender.to_string());
                             subscribers.retain(|s| s != &sender);
+                            raw_subscribers.retain(|s| s != &sender);
+                            commands.remove_owner(sender);
                         }
                         Message::Shutdown => return Ok(()),
                         _ =>  {}
@@ -140,3 +542,28 @@ pub async fn run(mut agent: Agent<(), Address>, config: &crate::config::Config)
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn say_channel() {
+        let say = parse_say(br##"{"channel": "#TOGGLEBIT", "text": "hi"}"##).unwrap();
+        assert_eq!(say.channel, "togglebit");
+
+        assert!(parse_say(br##"{"channel": "a :x\r\nJOIN #evil", "text": "hi"}"##).is_err());
+        assert!(parse_say(br#"{"channel": "toggle bit", "text": "hi"}"#).is_err());
+        assert!(parse_say(br##"{"channel": "togglebit", "text": "hi\r\nJOIN #evil"}"##).is_err());
+    }
+
+    #[test]
+    fn response_for_say() {
+        let say = parse_say(br#"{"channel": "togglebit", "text": "hi", "id": "1"}"#).unwrap();
+        let ok = response(ClientCommand::Say, Some("#TOGGLEBIT"), say.id.clone(), Ok(()));
+        assert_eq!(ok, ChatResponse::Ok { command: "say".into(), channel: Some("togglebit".into()), id: Some("1".into()) });
+
+        let err = response(ClientCommand::Say, Some(&say.channel), say.id, Err("full".into()));
+        assert!(matches!(err, ChatResponse::Error { id: Some(id), reason, .. } if id == "1" && reason == "full"));
+    }
+}
@@ -0,0 +1,338 @@
+use std::collections::{HashSet, VecDeque};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::config::OverflowPolicy;
+use crate::Address;
+
+// Twitch rate limits, see https://dev.twitch.tv/docs/irc/#rate-limits
+const MESSAGE_WINDOW: Duration = Duration::from_secs(30);
+const MESSAGE_LIMIT: usize = 20;
+// In channels where the bot is a moderator or the broadcaster
+const ELEVATED_MESSAGE_LIMIT: usize = 100;
+const JOIN_WINDOW: Duration = Duration::from_secs(10);
+const JOIN_LIMIT: usize = 20;
+
+/// What a queued line was sent for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientCommand {
+    Say,
+    Join,
+    Part,
+    /// Registering a chat command, or the reply to one
+    Command,
+}
+
+impl ClientCommand {
+    /// The name of the client command, as sent back in the response
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Say => "say",
+            Self::Join => "join",
+            Self::Part => "part",
+            Self::Command => "command",
+        }
+    }
+}
+
+/// A line waiting to be sent to Twitch
+#[derive(Debug)]
+pub struct Queued {
+    /// The client to acknowledge once the line is sent
+    pub sender: Option<Address>,
+    pub command: ClientCommand,
+    pub channel: String,
+    /// The id the client gave the command, sent back with the acknowledgement
+    pub id: Option<String>,
+    pub line: String,
+}
+
+// -----------------------------------------------------------------------------
+//     - Window -
+//     When lines were sent, for a sliding window rate limit
+// -----------------------------------------------------------------------------
+struct Window {
+    duration: Duration,
+    sent: VecDeque<Instant>,
+}
+
+impl Window {
+    fn new(duration: Duration) -> Self {
+        Self { duration, sent: VecDeque::new() }
+    }
+
+    fn prune(&mut self, now: Instant) {
+        while let Some(oldest) = self.sent.front() {
+            if now.duration_since(*oldest) < self.duration {
+                break;
+            }
+            self.sent.pop_front();
+        }
+    }
+
+    // The earliest a line can be sent without going over the limit
+    fn ready_at(&self, limit: usize, now: Instant) -> Instant {
+        match self.sent.len() < limit {
+            true => now,
+            false => self.sent[self.sent.len() - limit] + self.duration,
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Outbound -
+//     Everything sent to Twitch after logging in goes through here,
+//     so a misbehaving client can't get the account throttled.
+// -----------------------------------------------------------------------------
+pub struct Outbound {
+    policy: OverflowPolicy,
+    queue_size: usize,
+    messages: Window,
+    joins: Window,
+    // Channels with queued messages, in the order they get to send.
+    // A channel moves to the back after sending so a busy channel can't starve the others.
+    channels: VecDeque<(String, VecDeque<Queued>)>,
+    // JOINs and PARTs, with the channel they are for
+    pending_joins: VecDeque<(String, Queued)>,
+    // Channels where the bot is a moderator or the broadcaster
+    elevated: HashSet<String>,
+}
+
+impl Outbound {
+    pub fn new(policy: OverflowPolicy, queue_size: usize) -> Self {
+        Self {
+            policy,
+            queue_size,
+            messages: Window::new(MESSAGE_WINDOW),
+            joins: Window::new(JOIN_WINDOW),
+            channels: VecDeque::new(),
+            pending_joins: VecDeque::new(),
+            elevated: HashSet::new(),
+        }
+    }
+
+    /// Track if the bot is a moderator or the broadcaster in a channel (from the USERSTATE)
+    pub fn set_elevated(&mut self, channel: &str, elevated: bool) {
+        let channel = channel_key(channel);
+        match elevated {
+            true => self.elevated.insert(channel),
+            false => self.elevated.remove(&channel),
+        };
+    }
+
+    /// Queue a message for a channel.
+    /// If the queue of the channel is full the message that didn't make it is returned:
+    /// the oldest one when dropping, otherwise the new one.
+    /// When delaying, each client can have one message waiting past the end of a full queue.
+    pub fn push_message(&mut self, queued: Queued) -> Result<(), Queued> {
+        let channel = channel_key(&queued.channel);
+        let index = match self.channels.iter().position(|(c, _)| *c == channel) {
+            Some(index) => index,
+            None => {
+                self.channels.push_back((channel, VecDeque::new()));
+                self.channels.len() - 1
+            }
+        };
+        let queue = &mut self.channels[index].1;
+
+        if queue.len() < self.queue_size {
+            queue.push_back(queued);
+            return Ok(());
+        }
+
+        match self.policy {
+            OverflowPolicy::Reject => Err(queued),
+            OverflowPolicy::Drop => {
+                let oldest = queue.pop_front();
+                queue.push_back(queued);
+                oldest.map_or(Ok(()), Err)
+            }
+            OverflowPolicy::Delay => {
+                // The client is acknowledged once the message is sent,
+                // so a client that waits for that is slowed down rather than refused
+                if queue.iter().skip(self.queue_size).any(|waiting| waiting.sender == queued.sender) {
+                    return Err(queued);
+                }
+                queue.push_back(queued);
+                Ok(())
+            }
+        }
+    }
+
+    /// Queue a JOIN or a PART.
+    /// PARTs share the queue and the limit of the JOINs,
+    /// so they go out in the order the client asked for them.
+    pub fn push_membership(&mut self, queued: Queued) {
+        self.pending_joins.push_back((channel_key(&queued.channel), queued));
+    }
+
+    /// Take back a JOIN that wasn't sent yet, e.g when parting the channel before it's joined
+    pub fn cancel_join(&mut self, channel: &str) -> Option<Queued> {
+        let channel = channel_key(channel);
+        let index = self.pending_joins.iter().position(|(c, queued)| *c == channel && queued.command == ClientCommand::Join)?;
+        self.pending_joins.remove(index).map(|(_, queued)| queued)
+    }
+
+    /// Take the JOINs and PARTs not yet sent, e.g to join again after a reconnect
+    pub fn take_joins(&mut self) -> Vec<Queued> {
+        self.pending_joins.drain(..).map(|(_, queued)| queued).collect()
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.pending_joins.is_empty() || !self.channels.is_empty()
+    }
+
+    /// The next line that can be sent without going over the rate limits.
+    /// JOINs have their own limit and go first.
+    pub fn pop(&mut self, now: Instant) -> Option<Queued> {
+        self.joins.prune(now);
+        self.messages.prune(now);
+
+        if !self.pending_joins.is_empty() && self.joins.sent.len() < JOIN_LIMIT {
+            self.joins.sent.push_back(now);
+            return self.pending_joins.pop_front().map(|(_, queued)| queued);
+        }
+
+        let index = self.channels.iter().position(|(channel, _)| self.messages.sent.len() < self.limit(channel))?;
+        let (channel, mut queue) = self.channels.remove(index)?;
+        let queued = queue.pop_front()?;
+        if !queue.is_empty() {
+            self.channels.push_back((channel, queue));
+        }
+
+        self.messages.sent.push_back(now);
+        Some(queued)
+    }
+
+    /// When the next queued line can be sent, if there is one
+    pub fn next_ready(&self, now: Instant) -> Option<Instant> {
+        let join = self.pending_joins.front().map(|_| self.joins.ready_at(JOIN_LIMIT, now));
+        let messages = self.channels.iter().map(|(channel, _)| self.messages.ready_at(self.limit(channel), now));
+        join.into_iter().chain(messages).min()
+    }
+
+    fn limit(&self, channel: &str) -> usize {
+        match self.elevated.contains(channel) {
+            true => ELEVATED_MESSAGE_LIMIT,
+            false => MESSAGE_LIMIT,
+        }
+    }
+}
+
+// Channels are stored without the `#` and in lowercase,
+// as clients can send them either way
+fn channel_key(channel: &str) -> String {
+    channel.trim_start_matches('#').to_lowercase()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn queued(channel: &str, line: &str) -> Queued {
+        Queued { sender: None, command: ClientCommand::Say, channel: channel.into(), id: None, line: line.into() }
+    }
+
+    fn membership(command: ClientCommand, channel: &str) -> Queued {
+        let line = format!("{} #{}", command.as_str().to_uppercase(), channel);
+        Queued { sender: None, command, channel: channel.into(), id: None, line }
+    }
+
+    fn drain(outbound: &mut Outbound, now: Instant) -> Vec<String> {
+        std::iter::from_fn(|| outbound.pop(now)).map(|q| q.line).collect()
+    }
+
+    #[test]
+    fn message_limit() {
+        let now = Instant::now();
+        let mut outbound = Outbound::new(OverflowPolicy::Delay, 25);
+        for i in 0..25 {
+            outbound.push_message(queued("togglebit", &i.to_string())).unwrap();
+        }
+
+        assert_eq!(drain(&mut outbound, now).len(), MESSAGE_LIMIT);
+        assert_eq!(outbound.next_ready(now), Some(now + MESSAGE_WINDOW));
+        assert_eq!(drain(&mut outbound, now + MESSAGE_WINDOW).len(), 5);
+        assert!(!outbound.has_pending());
+    }
+
+    #[test]
+    fn elevated_limit() {
+        let now = Instant::now();
+        let mut outbound = Outbound::new(OverflowPolicy::Delay, 25);
+        outbound.set_elevated("togglebit", true);
+        for i in 0..25 {
+            outbound.push_message(queued("#TOGGLEBIT", &i.to_string())).unwrap();
+            outbound.push_message(queued("#other", &i.to_string())).unwrap();
+        }
+
+        // The other channel stops at the normal limit
+        let sent = drain(&mut outbound, now);
+        assert_eq!(sent.len(), 25 + MESSAGE_LIMIT / 2);
+    }
+
+    #[test]
+    fn channels_take_turns() {
+        let now = Instant::now();
+        let mut outbound = Outbound::new(OverflowPolicy::Delay, 10);
+        for line in ["a1", "a2", "a3"] {
+            outbound.push_message(queued("a", line)).unwrap();
+        }
+        outbound.push_message(queued("b", "b1")).unwrap();
+        outbound.push_membership(membership(ClientCommand::Join, "a"));
+
+        assert_eq!(drain(&mut outbound, now), vec!["JOIN #a", "a1", "b1", "a2", "a3"]);
+    }
+
+    #[test]
+    fn overflow_policies() {
+        let mut reject = Outbound::new(OverflowPolicy::Reject, 1);
+        reject.push_message(queued("a", "first")).unwrap();
+        assert_eq!(reject.push_message(queued("a", "second")).unwrap_err().line, "second");
+
+        let mut drop = Outbound::new(OverflowPolicy::Drop, 1);
+        drop.push_message(queued("a", "first")).unwrap();
+        assert_eq!(drop.push_message(queued("a", "second")).unwrap_err().line, "first");
+        assert_eq!(drain(&mut drop, Instant::now()), vec!["second"]);
+
+        // One message per client can wait past the end of the queue
+        let client = |line: &str, id: usize| Queued { sender: Some(Address::Connection(id)), ..queued("a", line) };
+        let mut delay = Outbound::new(OverflowPolicy::Delay, 1);
+        delay.push_message(client("first", 1)).unwrap();
+        delay.push_message(client("second", 1)).unwrap();
+        assert_eq!(delay.push_message(client("third", 1)).unwrap_err().line, "third");
+        delay.push_message(client("other", 2)).unwrap();
+        assert_eq!(drain(&mut delay, Instant::now()), vec!["first", "second", "other"]);
+    }
+
+    #[test]
+    fn part_while_join_queued() {
+        let now = Instant::now();
+        let mut outbound = Outbound::new(OverflowPolicy::Delay, 10);
+        for i in 0..JOIN_LIMIT {
+            outbound.push_membership(membership(ClientCommand::Join, &format!("c{}", i)));
+        }
+        assert_eq!(drain(&mut outbound, now).len(), JOIN_LIMIT);
+
+        // Over the limit, so the JOIN waits and can be taken back
+        outbound.push_membership(membership(ClientCommand::Join, "togglebit"));
+        assert_eq!(outbound.cancel_join("#TOGGLEBIT").unwrap().line, "JOIN #togglebit");
+        assert!(outbound.cancel_join("#togglebit").is_none());
+        assert!(!outbound.has_pending());
+
+        // PARTs wait for the limit too, and are not taken back
+        outbound.push_membership(membership(ClientCommand::Part, "c0"));
+        outbound.push_membership(membership(ClientCommand::Join, "c0"));
+        assert!(outbound.cancel_join("#c1").is_none());
+        assert!(drain(&mut outbound, now).is_empty());
+        assert_eq!(drain(&mut outbound, now + JOIN_WINDOW), vec!["PART #c0", "JOIN #c0"]);
+
+        // Taken out whole on a reconnect, so their senders can still be answered
+        outbound.push_membership(membership(ClientCommand::Part, "c1"));
+        outbound.push_membership(membership(ClientCommand::Join, "c2"));
+        let taken = outbound.take_joins().into_iter().map(|q| q.line).collect::<Vec<_>>();
+        assert_eq!(taken, vec!["PART #c1", "JOIN #c2"]);
+        assert!(!outbound.has_pending());
+    }
+}
@@ -1,6 +1,11 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
 
-use neotwitch::{Irc, IrcMessage};
+use neotwitch::{
+    Badge, Irc, IrcMessage, Notice, NoticeKind, RoomState, SubGiftInfo, SubInfo, SubPlan, UserNotice, UserNoticeKind,
+    UserState,
+};
 
 struct Cursor<'msg> {
     pos: usize,
@@ -31,39 +36,137 @@ impl<'msg> Cursor<'msg> {
         self.next_pat(pat, true)?;
         Some(())
     }
+
+    pub fn peek(&self) -> &'msg str {
+        &self.src[self.pos..]
+    }
+
+    /// Everything after the current position
+    pub fn rest(&mut self) -> &'msg str {
+        let rest = &self.src[self.pos..];
+        self.pos = self.src.len();
+        rest
+    }
 }
 
 const ACTION: &str = "\u{1}ACTION ";
 
-pub fn parse(raw: &str) -> Option<Irc> {
-    let mut cursor = Cursor::new(raw);
+// -----------------------------------------------------------------------------
+//     - Parse error -
+// -----------------------------------------------------------------------------
+#[derive(Debug, PartialEq)]
+pub enum ParseErrorKind {
+    MissingCommand,
+    /// The prefix is missing or doesn't contain a nick
+    InvalidPrefix,
+    MissingTag(&'static str),
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingCommand => write!(f, "missing command"),
+            Self::InvalidPrefix => write!(f, "invalid prefix"),
+            Self::MissingTag(tag) => write!(f, "missing or invalid tag \"{}\"", tag),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    /// The offending line
+    pub line: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {:?}", self.kind, self.line)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Split a websocket frame into IRC lines.
+/// Each line keeps its `\r\n`.
+pub fn lines(frame: &str) -> impl Iterator<Item = &str> {
+    frame.split_inclusive("\r\n").filter(|line| !line.trim().is_empty())
+}
+
+pub fn parse(raw: &str) -> Result<Irc, ParseError> {
+    parse_line(raw).map_err(|kind| ParseError { kind, line: raw.into() })
+}
+
+fn parse_line(raw: &str) -> Result<Irc, ParseErrorKind> {
+    let line = raw.strip_suffix("\r\n").unwrap_or(raw);
+    let mut cursor = Cursor::new(line);
 
     // Tags
     let tags = parse_tags(&mut cursor);
 
-    // Prefix
-    let prefix = cursor.next_pat(" ", false)?;
-    cursor.skip(" ");
+    // Prefix (optional)
+    let prefix = match cursor.peek().starts_with(':') {
+        true => {
+            let prefix = cursor.next_pat(" ", false).ok_or(ParseErrorKind::MissingCommand)?;
+            cursor.skip(" ");
+            prefix
+        }
+        false => "",
+    };
 
-    // Command
-    let command = cursor.next_pat(" ", false)?;
-    cursor.skip(" ");
+    // Command.
+    // Some commands (e.g GLOBALUSERSTATE) have no params
+    let command = match cursor.next_pat(" ", false) {
+        Some(command) => {
+            cursor.skip(" ");
+            command
+        }
+        None => cursor.rest(),
+    };
 
-    let params_and_trailing = cursor.next_pat("\r\n", false)?;
+    if command.is_empty() {
+        return Err(ParseErrorKind::MissingCommand);
+    }
 
-    let (param, trailing) = match params_and_trailing.find(':') {
-        Some(pos) => {
-            let (param, trailing) = params_and_trailing.split_at(pos + 1);
-            (&param[1..pos - 1], trailing)
+    // Params and trailing, e.g `#channel :message`.
+    // The trailing part can contain both spaces and colons.
+    let params_and_trailing = cursor.rest();
+    let (param, raw_trailing) = match params_and_trailing.strip_prefix(':') {
+        Some(trailing) => ("", Some(trailing)),
+        None => match params_and_trailing.split_once(" :") {
+            Some((param, trailing)) => (param, Some(trailing)),
+            None => (params_and_trailing, None),
         },
-        None => (params_and_trailing, ""),
     };
+    let trailing = raw_trailing.unwrap_or("");
 
-    match command {
-        "CLEARCHAT" => Some(Irc::ClearChat),
-        "PRIVMSG" => Some(Irc::Message(parse_msg(prefix, param, trailing, tags)?)),
-        _ => None,
-    }
+    let irc = match command {
+        "CLEARCHAT" => parse_clear_chat(param, trailing, &tags),
+        "CLEARMSG" => parse_clear_message(param, &tags)?,
+        "PRIVMSG" => Irc::Message(parse_msg(prefix, param, trailing, tags)?),
+        "NOTICE" => Irc::Notice(parse_notice(param, trailing, &tags)),
+        "USERSTATE" => Irc::UserState(parse_user_state(Some(param), tags)),
+        "GLOBALUSERSTATE" => Irc::GlobalUserState(parse_user_state(None, tags)),
+        "ROOMSTATE" => Irc::RoomState(parse_room_state(param, &tags)),
+        "USERNOTICE" => Irc::UserNotice(parse_user_notice(param, trailing, tags)?),
+        "JOIN" => Irc::Join { channel: channel_param(param), login: nick(prefix)?.into() },
+        "PART" => Irc::Part { channel: channel_param(param), login: nick(prefix)?.into() },
+        "353" => Irc::Names {
+            channel: channel_param(param),
+            logins: trailing.split(' ').filter(|l| !l.is_empty()).map(String::from).collect(),
+        },
+        "366" => Irc::NamesEnd { channel: channel_param(param) },
+        "RECONNECT" => Irc::Reconnect,
+        _ => Irc::Raw {
+            tags,
+            prefix: prefix.trim_start_matches(':').into(),
+            command: command.into(),
+            params: param.split(' ').filter(|p| !p.is_empty()).map(String::from).collect(),
+            trailing: raw_trailing.map(String::from),
+        },
+    };
+
+    Ok(irc)
 }
 
 fn parse_msg(
@@ -71,30 +174,226 @@ fn parse_msg(
     param: &str,
     trailing: &str,
     tags: HashMap<String, String>,
-) -> Option<IrcMessage> {
+) -> Result<IrcMessage, ParseErrorKind> {
 
     // Username
+    let nick = nick(prefix)?;
+
+    // Channel
+    let channel = param.trim_start_matches('#');
+
+    // Message.
+    // The closing \u{1} of an action is sometimes missing
+    let (msg, is_action) = match trailing.strip_prefix(ACTION) {
+        Some(action) => (action.strip_suffix('\u{1}').unwrap_or(action), true),
+        None => (trailing, false),
+    };
+
+    Ok(IrcMessage::new(nick.into(), channel.into(), msg.into(), is_action, tags))
+}
+
+// The nick from a prefix like `:nick!nick@nick.tmi.twitch.tv`
+fn nick(prefix: &str) -> Result<&str, ParseErrorKind> {
     let mut cursor = Cursor::new(prefix);
     cursor.skip(":");
-    let nick = cursor.next_pat("!", false)?;
+    cursor.next_pat("!", false).ok_or(ParseErrorKind::InvalidPrefix)
+}
 
-    // Channel
-    let channel = param;
+// The channel is the last param for numeric replies, e.g `nick = #channel`
+fn channel_param(param: &str) -> String {
+    let channel = param.rsplit(' ').next().unwrap_or(param);
+    channel.trim_start_matches('#').into()
+}
 
-    // Message
-    let message = trailing;
+fn parse_clear_chat(param: &str, trailing: &str, tags: &HashMap<String, String>) -> Irc {
+    let target_user = match trailing {
+        "" => None,
+        user => Some(user.into()),
+    };
 
-    // cursor.skip(":")?;
-    let (msg, is_action) = {
-        let mut msg = message;
-        let is_action = msg.starts_with(ACTION);
-        if is_action {
-            msg = &msg[ACTION.len()..msg.len() - 1];
-        }
-        (msg, is_action)
+    Irc::ClearChat {
+        channel: param.trim_start_matches('#').into(),
+        target_user,
+        target_user_id: tags.get("target-user-id").cloned(),
+        ban_duration: tags.get("ban-duration").and_then(|d| d.parse().ok()),
+    }
+}
+
+fn parse_clear_message(param: &str, tags: &HashMap<String, String>) -> Result<Irc, ParseErrorKind> {
+    let msg = Irc::ClearMessage {
+        channel: param.trim_start_matches('#').into(),
+        login: tag(tags, "login")?,
+        target_msg_id: tag(tags, "target-msg-id")?,
+    };
+
+    Ok(msg)
+}
+
+fn parse_notice(param: &str, trailing: &str, tags: &HashMap<String, String>) -> Notice {
+    let channel = match param.trim_start_matches('#') {
+        "" | "*" => None,
+        channel => Some(channel.into()),
+    };
+
+    let kind = match tags.get("msg-id") {
+        Some(msg_id) => NoticeKind::from_msg_id(msg_id),
+        // Failed logins are sent without tags
+        None if channel.is_none() && is_login_failure(trailing) => NoticeKind::LoginFailed,
+        None => NoticeKind::Unknown { msg_id: String::new() },
+    };
+
+    Notice { channel, kind, message: trailing.into() }
+}
+
+fn is_login_failure(message: &str) -> bool {
+    matches!(message, "Login authentication failed" | "Improperly formatted auth" | "Invalid NICK")
+}
+
+fn parse_user_state(param: Option<&str>, tags: HashMap<String, String>) -> UserState {
+    let emote_sets = tags
+        .get("emote-sets")
+        .map(|sets| sets.split(',').filter(|s| !s.is_empty()).map(String::from).collect())
+        .unwrap_or_default();
+
+    UserState {
+        channel: param.map(|p| p.trim_start_matches('#').into()),
+        user_id: tags.get("user-id").cloned(),
+        display_name: tags.get("display-name").cloned().unwrap_or_default(),
+        color: tags.get("color").filter(|c| !c.is_empty()).cloned(),
+        emote_sets,
+        badges: tags.get("badges").map(|b| Badge::parse_list(b)).unwrap_or_default(),
+        moderator: tags.get("mod").map(|m| m == "1").unwrap_or(false),
+        tags,
+    }
+}
+
+fn parse_room_state(param: &str, tags: &HashMap<String, String>) -> RoomState {
+    let flag = |key: &str| tags.get(key).map(|v| v == "1");
+
+    RoomState {
+        channel: param.trim_start_matches('#').into(),
+        room_id: tags.get("room-id").cloned(),
+        emote_only: flag("emote-only"),
+        followers_only: tags.get("followers-only").and_then(|v| v.parse().ok()),
+        r9k: flag("r9k"),
+        slow: tags.get("slow").and_then(|v| v.parse().ok()),
+        subs_only: flag("subs-only"),
+    }
+}
+
+fn parse_user_notice(
+    param: &str,
+    trailing: &str,
+    tags: HashMap<String, String>,
+) -> Result<UserNotice, ParseErrorKind> {
+    let channel = param.trim_start_matches('#');
+    let login = tag(&tags, "login")?;
+    let msg_id = tag(&tags, "msg-id")?;
+    let system_msg = tags.get("system-msg").cloned().unwrap_or_default();
+
+    // The user entered message is optional
+    let message = match trailing {
+        "" => None,
+        msg => Some(msg.into()),
+    };
+
+    // If the params for a known msg-id are missing (or Twitch changed them)
+    // it's better to pass the notice on as unknown than to drop it.
+    let kind = parse_notice_kind(&msg_id, &tags).unwrap_or(UserNoticeKind::Unknown { msg_id });
+
+    Ok(UserNotice::new(login, channel.into(), system_msg, message, kind, tags))
+}
+
+fn parse_notice_kind(msg_id: &str, tags: &HashMap<String, String>) -> Option<UserNoticeKind> {
+    let kind = match msg_id {
+        "sub" => UserNoticeKind::Sub(parse_sub_info(tags)?),
+        "resub" => UserNoticeKind::Resub(parse_sub_info(tags)?),
+        "subgift" => UserNoticeKind::SubGift(parse_sub_gift_info(tags)?),
+        "anonsubgift" => UserNoticeKind::AnonSubGift(parse_sub_gift_info(tags)?),
+        "submysterygift" => UserNoticeKind::SubMysteryGift {
+            mass_gift_count: msg_param(tags, "mass-gift-count")?,
+            sender_count: msg_param(tags, "sender-count"),
+            sub_plan: sub_plan(tags)?,
+        },
+        "giftpaidupgrade" => UserNoticeKind::GiftPaidUpgrade {
+            sender_login: msg_param(tags, "sender-login")?,
+            sender_name: msg_param(tags, "sender-name")?,
+            promo_name: msg_param(tags, "promo-name"),
+            promo_gift_total: msg_param(tags, "promo-gift-total"),
+        },
+        "anongiftpaidupgrade" => UserNoticeKind::AnonGiftPaidUpgrade {
+            promo_name: msg_param(tags, "promo-name"),
+            promo_gift_total: msg_param(tags, "promo-gift-total"),
+        },
+        "primepaidupgrade" => UserNoticeKind::PrimePaidUpgrade {
+            sub_plan: sub_plan(tags)?,
+        },
+        "raid" => UserNoticeKind::Raid {
+            display_name: msg_param(tags, "displayName")?,
+            login: msg_param(tags, "login")?,
+            viewer_count: msg_param(tags, "viewerCount")?,
+        },
+        "unraid" => UserNoticeKind::Unraid,
+        "ritual" => UserNoticeKind::Ritual {
+            name: msg_param(tags, "ritual-name")?,
+        },
+        "bitsbadgetier" => UserNoticeKind::BitsBadgeTier {
+            threshold: msg_param(tags, "threshold")?,
+        },
+        "announcement" => UserNoticeKind::Announcement {
+            color: msg_param(tags, "color").unwrap_or_else(|| "PRIMARY".into()),
+        },
+        _ => return None,
     };
 
-    Some(IrcMessage::new(nick.into(), channel.into(), msg.into(), is_action, tags))
+    Some(kind)
+}
+
+fn parse_sub_info(tags: &HashMap<String, String>) -> Option<SubInfo> {
+    // The streak is only included if the user chose to share it
+    let streak_months = match tags.get("msg-param-should-share-streak").map(String::as_str) {
+        Some("1") => msg_param(tags, "streak-months"),
+        _ => None,
+    };
+
+    let info = SubInfo {
+        cumulative_months: msg_param(tags, "cumulative-months")?,
+        streak_months,
+        sub_plan: sub_plan(tags)?,
+        sub_plan_name: msg_param(tags, "sub-plan-name").unwrap_or_default(),
+        multimonth_duration: msg_param(tags, "multimonth-duration"),
+        was_gifted: msg_param(tags, "was-gifted").unwrap_or(false),
+    };
+
+    Some(info)
+}
+
+fn parse_sub_gift_info(tags: &HashMap<String, String>) -> Option<SubGiftInfo> {
+    let info = SubGiftInfo {
+        months: msg_param(tags, "months")?,
+        recipient_id: msg_param(tags, "recipient-id")?,
+        recipient_login: msg_param(tags, "recipient-user-name")?,
+        recipient_display_name: msg_param(tags, "recipient-display-name")?,
+        sub_plan: sub_plan(tags)?,
+        sub_plan_name: msg_param(tags, "sub-plan-name").unwrap_or_default(),
+        gift_months: msg_param(tags, "gift-months"),
+    };
+
+    Some(info)
+}
+
+fn sub_plan(tags: &HashMap<String, String>) -> Option<SubPlan> {
+    SubPlan::from_id(tags.get("msg-param-sub-plan")?)
+}
+
+// Get a required tag
+fn tag(tags: &HashMap<String, String>, name: &'static str) -> Result<String, ParseErrorKind> {
+    tags.get(name).cloned().ok_or(ParseErrorKind::MissingTag(name))
+}
+
+// Get and parse a `msg-param-*` tag
+fn msg_param<T: FromStr>(tags: &HashMap<String, String>, name: &str) -> Option<T> {
+    tags.get(&format!("msg-param-{}", name))?.parse().ok()
 }
 
 fn parse_tags(cursor: &mut Cursor) -> HashMap<String, String> {
@@ -104,28 +403,59 @@ fn parse_tags(cursor: &mut Cursor) -> HashMap<String, String> {
         false => return key_values,
     }
 
-    let tags = match cursor.next_pat(" ", true) {
+    let tags = match cursor.next_pat(" ", false) {
         Some(t) => t,
         None => return key_values,
     };
+    cursor.skip(" ");
 
     for tags in tags.split(';') {
         let mut tag = tags.splitn(2, '=');
         let key = tag.next().expect("The key should always be there");
-        let val = match tag.next() {
-            Some(t) => t,
-            None => continue,
-        };
+        if key.is_empty() {
+            continue;
+        }
 
-        key_values.insert(key.into(), val.into());
+        // A tag without a value (`key` or `key=`) is kept as an empty string
+        let val = tag.next().map(unescape_tag_value).unwrap_or_default();
+
+        key_values.insert(key.into(), val);
     }
 
     key_values
 }
 
+// Unescape a tag value according to the IRCv3 spec:
+// https://ircv3.net/specs/extensions/message-tags#escaping-values
+fn unescape_tag_value(val: &str) -> String {
+    let mut unescaped = String::with_capacity(val.len());
+    let mut chars = val.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        // An invalid escape drops the backslash, and a trailing backslash is removed
+        match chars.next() {
+            Some(':') => unescaped.push(';'),
+            Some('s') => unescaped.push(' '),
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            Some(c) => unescaped.push(c),
+            None => {}
+        }
+    }
+
+    unescaped
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use chrono::{Local, TimeZone, Utc};
+    use neotwitch::{Badge, ReplyContext, Say, UserType, DEFAULT_TIMESTAMP_FORMAT};
 
     #[test]
     fn test_parse_message_with_tags() {
@@ -134,28 +464,501 @@ mod test {
             Irc::Message(msg) => msg,
             _ => panic!("Incorrect message type")
         };
-        assert_eq!(msg.user, "togglebit");
+        assert_eq!(msg.nick, "togglebit");
         assert_eq!(msg.tags["color"], "#5F9EA0");
     }
 
+    // Shared by the tests of the tag derived fields
+    fn tagged_message() -> IrcMessage {
+        let input = "@badge-info=subscriber/17;badges=broadcaster/1,subscriber/3009;client-nonce=d72eefc2eb1c108b085ecb9c2492afa4;color=#5F9EA0;display-name=togglebit;emotes=;flags=;id=0136c224-4c6d-4554-b5ad-17b6a70ad96d;mod=0;room-id=474725923;subscriber=1;tmi-sent-ts=1632681408560;turbo=0;user-id=474725923;user-type= :togglebit!togglebit@togglebit.tmi.twitch.tv PRIVMSG #togglebit :test\r\n";
+        message(input)
+    }
+
+    #[test]
+    fn parse_badges() {
+        let msg = tagged_message();
+        assert_eq!(msg.badges[0], Badge { name: "broadcaster".into(), version: "1".into() });
+        assert!(msg.is_broadcaster());
+        assert!(msg.is_subscriber());
+        assert!(!msg.is_moderator());
+        assert!(!msg.is_vip());
+        assert_eq!(msg.subscribed_months(), Some(17));
+    }
+
+    #[test]
+    fn parse_sent_timestamp() {
+        let msg = tagged_message();
+        assert_eq!(msg.timestamp.timestamp_millis(), 1632681408560);
+        assert_eq!(msg.timestamp(DEFAULT_TIMESTAMP_FORMAT, &Utc), "18:36:48");
+    }
+
+    #[test]
+    fn parse_chat_user() {
+        let msg = tagged_message();
+        assert_eq!(msg.user.display_name, "togglebit");
+        assert_eq!(msg.user.id.as_deref(), Some("474725923"));
+        assert_eq!(msg.user.user_type, UserType::Normal);
+        assert_eq!(msg.color(), Some("#5F9EA0"));
+    }
+
     #[test]
     fn test_parse_message_without_tags() {
         let input = ":randomuser!randomuser@randomuser.tmi.twitch.tv PRIVMSG #togglebit :some random message\r\n";
         let msg = parse(input).unwrap();
-        assert!(matches!(msg, Irc::Message(IrcMessage { user: Cow::Borrowed("randomuser"), .. })));
+        assert!(matches!(msg, Irc::Message(IrcMessage { ref nick, .. }) if nick == "randomuser"));
+    }
+
+    #[test]
+    fn multiple_lines_in_frame() {
+        let frame = ":tmi.twitch.tv CLEARCHAT #togglebit\r\n:randomuser!randomuser@randomuser.tmi.twitch.tv PRIVMSG #togglebit :hello\r\n";
+        let messages = lines(frame).filter_map(|line| parse(line).ok()).collect::<Vec<_>>();
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(messages[0], Irc::ClearChat { .. }));
+        assert!(matches!(messages[1], Irc::Message(_)));
+    }
+
+    #[test]
+    fn invalid_lines_are_rejected() {
+        let inputs = [
+            "",
+            "\r\n",
+            "@",
+            ":",
+            ": PRIVMSG",
+            ":tmi.twitch.tv PRIVMSG :no channel\r\n",
+            "@login=randomuser :tmi.twitch.tv CLEARMSG #togglebit :gone\r\n",
+        ];
+
+        for input in inputs {
+            assert!(parse(input).is_err(), "{:?} should fail", input);
+        }
+    }
+
+    #[test]
+    fn unsupported_command() {
+        let msg = parse(":tmi.twitch.tv CAP * ACK :twitch.tv/tags\r\n").unwrap();
+        match msg {
+            Irc::Raw { tags, prefix, command, params, trailing } => {
+                assert!(tags.is_empty());
+                assert_eq!(prefix, "tmi.twitch.tv");
+                assert_eq!(command, "CAP");
+                assert_eq!(params, vec!["*", "ACK"]);
+                assert_eq!(trailing.as_deref(), Some("twitch.tv/tags"));
+            }
+            _ => panic!("Incorrect message type")
+        }
+    }
+
+    #[test]
+    fn parse_unterminated_action() {
+        let input = ":randomuser!randomuser@randomuser.tmi.twitch.tv PRIVMSG #togglebit :\u{1}ACTION \r\n";
+        let msg = parse(input).unwrap();
+        assert!(matches!(msg, Irc::Message(IrcMessage { action: true, ref message, .. }) if message.is_empty()));
+
+        let input = ":randomuser!randomuser@randomuser.tmi.twitch.tv PRIVMSG #togglebit :\u{1}ACTION waves 🦀\r\n";
+        let msg = parse(input).unwrap();
+        assert!(matches!(msg, Irc::Message(IrcMessage { ref message, .. }) if message == "waves 🦀"));
+    }
+
+    #[test]
+    fn parse_membership() {
+        let input = ":randomuser!randomuser@randomuser.tmi.twitch.tv JOIN #togglebit\r\n";
+        let msg = parse(input).unwrap();
+        assert!(matches!(msg, Irc::Join { ref channel, ref login } if channel == "togglebit" && login == "randomuser"));
+
+        let input = ":randomuser!randomuser@randomuser.tmi.twitch.tv PART #togglebit\r\n";
+        let msg = parse(input).unwrap();
+        assert!(matches!(msg, Irc::Part { ref login, .. } if login == "randomuser"));
+
+        let input = ":neobot.tmi.twitch.tv 353 neobot = #togglebit :togglebit randomuser neobot\r\n";
+        match parse(input).unwrap() {
+            Irc::Names { channel, logins } => {
+                assert_eq!(channel, "togglebit");
+                assert_eq!(logins, vec!["togglebit", "randomuser", "neobot"]);
+            }
+            _ => panic!("Incorrect message type")
+        }
+
+        let input = ":neobot.tmi.twitch.tv 366 neobot #togglebit :End of /NAMES list\r\n";
+        let msg = parse(input).unwrap();
+        assert!(matches!(msg, Irc::NamesEnd { ref channel } if channel == "togglebit"));
+    }
+
+    #[test]
+    fn parse_reconnect() {
+        let msg = parse(":tmi.twitch.tv RECONNECT\r\n").unwrap();
+        assert!(matches!(msg, Irc::Reconnect));
+    }
+
+    #[test]
+    fn round_trip() {
+        let inputs = [
+            "@badge-info=subscriber/17;badges=broadcaster/1,subscriber/3009;color=#5F9EA0;display-name=togglebit;emotes=25:0-4;id=0136c224-4c6d-4554-b5ad-17b6a70ad96d;reply-parent-msg-body=semi\\:colon\\sand\\\\slash;tmi-sent-ts=1632681408560;user-type= :togglebit!togglebit@togglebit.tmi.twitch.tv PRIVMSG #togglebit :Kappa test\r\n",
+            ":randomuser!randomuser@randomuser.tmi.twitch.tv PRIVMSG #togglebit :\u{1}ACTION waves\u{1}\r\n",
+            "@ban-duration=350;room-id=474725923;target-user-id=12345 :tmi.twitch.tv CLEARCHAT #togglebit :randomuser\r\n",
+            ":tmi.twitch.tv CLEARCHAT #togglebit\r\n",
+            "@login=randomuser;target-msg-id=0136c224-4c6d-4554-b5ad-17b6a70ad96d :tmi.twitch.tv CLEARMSG #togglebit :bye\r\n",
+            "@login=raider;msg-id=raid;msg-param-displayName=Raider;msg-param-login=raider;msg-param-viewerCount=15;system-msg=15\\sraiders! :tmi.twitch.tv USERNOTICE #togglebit\r\n",
+            "@emote-only=0;followers-only=-1;r9k=0;room-id=474725923;slow=0;subs-only=0 :tmi.twitch.tv ROOMSTATE #togglebit\r\n",
+            "@msg-id=slow_on :tmi.twitch.tv NOTICE #togglebit :This room is now in slow mode.\r\n",
+            ":tmi.twitch.tv NOTICE * :Login authentication failed\r\n",
+            "@badges=moderator/1;color=;display-name=NeoBot;emote-sets=0;mod=1 :tmi.twitch.tv USERSTATE #togglebit\r\n",
+            "@color=#5F9EA0;display-name=NeoBot;emote-sets=0,300374282;user-id=474725923 :tmi.twitch.tv GLOBALUSERSTATE\r\n",
+            ":randomuser!randomuser@randomuser.tmi.twitch.tv JOIN #togglebit\r\n",
+            ":randomuser!randomuser@randomuser.tmi.twitch.tv PART #togglebit\r\n",
+            ":neobot.tmi.twitch.tv 353 neobot = #togglebit :togglebit randomuser\r\n",
+            ":neobot.tmi.twitch.tv 366 neobot #togglebit :End of /NAMES list\r\n",
+            ":tmi.twitch.tv RECONNECT\r\n",
+            "@badges=;color=;display-name=RandomUser;user-id=12345 :randomuser!randomuser@randomuser.tmi.twitch.tv WHISPER neobot :psst\r\n",
+            ":tmi.twitch.tv HOSTTARGET #togglebit :- 0\r\n",
+            ":tmi.twitch.tv 001 neobot :Welcome, GLHF!\r\n",
+            "PING :tmi.twitch.tv\r\n",
+        ];
+
+        for input in inputs {
+            let msg = parse(input).unwrap();
+            let raw = msg.to_raw();
+            assert_eq!(parse(&raw).unwrap(), msg, "{:?} -> {:?}", input, raw);
+        }
+    }
+
+    #[test]
+    fn chatters_as_names() {
+        let chatters = Irc::Chatters { channel: "togglebit".into(), chatters: vec!["togglebit".into(), "randomuser".into()] };
+        match parse(&chatters.to_raw()).unwrap() {
+            Irc::Names { channel, logins } => {
+                assert_eq!(channel, "togglebit");
+                assert_eq!(logins, vec!["togglebit", "randomuser"]);
+            }
+            _ => panic!("Incorrect message type")
+        }
+    }
+
+    #[test]
+    fn round_trip_constructed() {
+        let sent = Local.timestamp_millis_opt(1632681408560).unwrap();
+
+        // The typed fields are written to the tags, so only the tags differ after parsing
+        let kind = UserNoticeKind::Raid { display_name: "Raider".into(), login: "raider".into(), viewer_count: 15 };
+        let mut notice = UserNotice::new("raider".into(), "togglebit".into(), "15 raiders!".into(), None, kind, HashMap::new());
+        notice.timestamp = sent;
+        let parsed = user_notice(&notice.to_irc_line());
+        assert_eq!(parsed.tags["msg-id"], "raid");
+        assert_eq!(UserNotice { tags: HashMap::new(), ..parsed }, notice);
+
+        let mut msg = IrcMessage::new("randomuser".into(), "togglebit".into(), "hi".into(), false, HashMap::new());
+        msg.timestamp = sent;
+        msg.user.display_name = "RandomUser".into();
+        msg.user.color = Some("#1E90FF".into());
+        msg.user.subscriber = true;
+        msg.badges = vec![Badge { name: "subscriber".into(), version: "6".into() }];
+        msg.reply = Some(ReplyContext {
+            parent_msg_id: "b34ccfc7-4977-403a-8a94-33c6bac34fb8".into(),
+            parent_user_id: None,
+            parent_user_login: "togglebit".into(),
+            parent_display_name: "togglebit".into(),
+            parent_msg_body: "hello; world".into(),
+            thread_parent_msg_id: None,
+            thread_parent_user_login: None,
+        });
+        let parsed = message(&msg.to_irc_line());
+        assert_eq!(IrcMessage { tags: HashMap::new(), ..parsed }, msg);
+
+        let state = UserState {
+            channel: Some("togglebit".into()),
+            user_id: None,
+            display_name: "NeoBot".into(),
+            color: None,
+            emote_sets: vec!["0".into()],
+            badges: vec![Badge { name: "moderator".into(), version: "1".into() }],
+            moderator: true,
+            tags: HashMap::new(),
+        };
+        let parsed = user_state(&Irc::UserState(state.clone()).to_raw());
+        assert_eq!(UserState { tags: HashMap::new(), ..parsed }, state);
+    }
+
+    #[test]
+    fn unescape_tags() {
+        let input = "@system-msg=a\\sb\\:c\\\\d\\ne\\x\\;empty=;flag;user-type= :tmi.twitch.tv USERNOTICE #togglebit\r\n";
+        let mut cursor = Cursor::new(input);
+        let tags = parse_tags(&mut cursor);
+        assert_eq!(tags["system-msg"], "a b;c\\d\nex");
+        assert_eq!(tags["empty"], "");
+        assert_eq!(tags["flag"], "");
+        assert_eq!(tags["user-type"], "");
     }
 
     #[test]
     fn parse_command() {
         let input = "@room-id=474725923;tmi-sent-ts=1632686729261 :tmi.twitch.tv CLEARCHAT #togglebit\r\n";
         let msg = parse(input).unwrap();
-        assert!(matches!(msg, Irc::ClearChat));
+        assert!(matches!(msg, Irc::ClearChat { target_user: None, .. }));
+    }
+
+    #[test]
+    fn parse_timeout() {
+        let input = "@ban-duration=350;room-id=474725923;target-user-id=12345;tmi-sent-ts=1632686729261 :tmi.twitch.tv CLEARCHAT #togglebit :randomuser\r\n";
+        let msg = parse(input).unwrap();
+        match msg {
+            Irc::ClearChat { channel, target_user, target_user_id, ban_duration } => {
+                assert_eq!(channel, "togglebit");
+                assert_eq!(target_user.as_deref(), Some("randomuser"));
+                assert_eq!(target_user_id.as_deref(), Some("12345"));
+                assert_eq!(ban_duration, Some(350));
+            }
+            _ => panic!("Incorrect message type")
+        }
+    }
+
+    #[test]
+    fn parse_ban() {
+        let input = "@room-id=474725923;target-user-id=12345;tmi-sent-ts=1632686729261 :tmi.twitch.tv CLEARCHAT #togglebit :randomuser\r\n";
+        let msg = parse(input).unwrap();
+        assert!(matches!(msg, Irc::ClearChat { target_user: Some(_), ban_duration: None, .. }));
+    }
+
+    #[test]
+    fn parse_clear_message() {
+        let input = "@login=randomuser;room-id=;target-msg-id=0136c224-4c6d-4554-b5ad-17b6a70ad96d;tmi-sent-ts=1632686729261 :tmi.twitch.tv CLEARMSG #togglebit :some random message\r\n";
+        let msg = parse(input).unwrap();
+        match msg {
+            Irc::ClearMessage { channel, login, target_msg_id } => {
+                assert_eq!(channel, "togglebit");
+                assert_eq!(login, "randomuser");
+                assert_eq!(target_msg_id, "0136c224-4c6d-4554-b5ad-17b6a70ad96d");
+            }
+            _ => panic!("Incorrect message type")
+        }
+    }
+
+    #[test]
+    fn parse_reply() {
+        let input = "@badge-info=;badges=;color=;display-name=RandomUser;emotes=;flags=;id=7f1336e4-f84a-4510-809d-e57bf50af0cc;mod=0;reply-parent-display-name=togglebit;reply-parent-msg-body=how\\sare\\syou?;reply-parent-msg-id=0136c224-4c6d-4554-b5ad-17b6a70ad96d;reply-parent-user-id=474725923;reply-parent-user-login=togglebit;reply-thread-parent-msg-id=0136c224-4c6d-4554-b5ad-17b6a70ad96d;reply-thread-parent-user-login=togglebit;room-id=474725923;subscriber=0;tmi-sent-ts=1632681408560;turbo=0;user-id=12345;user-type= :randomuser!randomuser@randomuser.tmi.twitch.tv PRIVMSG #togglebit :@togglebit good, thanks\r\n";
+        let msg = message(input);
+        let reply = msg.reply.as_ref().unwrap();
+        assert_eq!(reply.parent_user_login, "togglebit");
+        assert_eq!(reply.parent_msg_body, "how are you?");
+        assert_eq!(reply.thread_parent_msg_id.as_deref(), Some("0136c224-4c6d-4554-b5ad-17b6a70ad96d"));
+        assert_eq!(msg.message, "@togglebit good, thanks");
+        assert_eq!(msg.message_without_reply_mention(), "good, thanks");
+    }
+
+    #[test]
+    fn parse_localized_display_name() {
+        let input = "@badges=;color=;display-name=トグル;mod=1;user-id=12345;user-type=mod :toggle!toggle@toggle.tmi.twitch.tv PRIVMSG #togglebit :こんにちは\r\n";
+        let msg = message(input);
+        assert_eq!(msg.color(), None);
+        assert_eq!(msg.display_color(None), neotwitch::default_color("toggle"));
+        assert!(msg.user.moderator);
+        assert_eq!(msg.user.user_type, UserType::Mod);
+        assert!(msg.user.has_localized_name());
+        assert_eq!(msg.user.name(), "トグル (toggle)");
+    }
+
+    #[test]
+    fn parse_shared_chat() {
+        let input = "@badges=;color=;display-name=RandomUser;id=11111111-4c6d-4554-b5ad-17b6a70ad96d;room-id=474725923;source-badges=moderator/1;source-id=0136c224-4c6d-4554-b5ad-17b6a70ad96d;source-room-id=12345678;user-id=12345 :randomuser!randomuser@randomuser.tmi.twitch.tv PRIVMSG #togglebit :hello both\r\n";
+        let msg = message(input);
+        let source = msg.source.as_ref().unwrap();
+        assert_eq!(source.message_id, "0136c224-4c6d-4554-b5ad-17b6a70ad96d");
+        assert_eq!(source.badges[0].name, "moderator");
+        assert_eq!(msg.origin_room_id(), Some("12345678"));
+        assert!(msg.is_from_other_room());
+    }
+
+    #[test]
+    fn parse_cheer() {
+        let input = "@badge-info=;badges=bits/100;bits=600;color=;display-name=RandomUser;emotes=;id=7f1336e4-f84a-4510-809d-e57bf50af0cc;mod=0;room-id=474725923;subscriber=0;tmi-sent-ts=1632681408560;user-id=12345;user-type= :randomuser!randomuser@randomuser.tmi.twitch.tv PRIVMSG #togglebit :Cheer100 nice stream PogChamp500\r\n";
+        let msg = message(input);
+        assert_eq!(msg.bits(), Some(600));
+        let cheermotes = msg.cheermotes();
+        assert_eq!(cheermotes.len(), 2);
+        assert_eq!(cheermotes[1].prefix, "PogChamp");
+        assert_eq!(cheermotes[1].tier, 100);
+        assert_eq!(cheermotes.iter().map(|c| c.amount).sum::<usize>(), 600);
+    }
+
+    #[test]
+    fn parse_mentions_and_links() {
+        let input = "@badge-info=;badges=;color=;display-name=RandomUser;emotes=;id=1;mod=0;room-id=474725923;subscriber=0;tmi-sent-ts=1632681408560;user-id=12345;user-type= :randomuser!randomuser@randomuser.tmi.twitch.tv PRIVMSG #togglebit :@ToggleBit @NeoBot! check twitch.tv/togglebit.\r\n";
+        let msg = message(input);
+
+        let mentions = msg.mentions();
+        assert_eq!(mentions.len(), 2);
+        assert_eq!(mentions[0].login, "togglebit");
+        assert_eq!(&msg.message[mentions[1].span.clone()], "@NeoBot");
+
+        let mentions = msg.mentions_of(&["neobot"]);
+        assert_eq!(mentions.len(), 1);
+        assert_eq!(mentions[0].login, "neobot");
+
+        let links = msg.links();
+        assert_eq!(links.len(), 1);
+        assert_eq!(links[0].url, "twitch.tv/togglebit");
+        assert_eq!(&msg.message[links[0].span.clone()], "twitch.tv/togglebit");
+    }
+
+    #[test]
+    fn say_as_privmsg() {
+        let say = Say { channel: "#togglebit".into(), text: "hello; world".into(), reply_to: Some("abc-123".into()), id: None };
+        let line = say.to_irc_line();
+        assert_eq!(line, "@reply-parent-msg-id=abc-123 PRIVMSG #togglebit :hello; world\r\n");
+
+        let echo = line.replacen(" PRIVMSG", " :neobot!neobot@neobot.tmi.twitch.tv PRIVMSG", 1);
+        let msg = message(&echo);
+        assert_eq!(msg.channel, "togglebit");
+        assert_eq!(msg.message, "hello; world");
+    }
+
+    #[test]
+    fn say_without_line_breaks() {
+        let say = Say { channel: "a :x\r\nJOIN #evil".into(), text: "hi\r\nPART #togglebit".into(), reply_to: None, id: None };
+        assert_eq!(say.to_irc_line(), "PRIVMSG #a:xJOIN#evil :hi  PART #togglebit\r\n");
     }
 
     #[test]
     fn parse_action() {
         let input = "@badge-info=subscriber/17;badges=broadcaster/1,subscriber/3009;color=#5F9EA0;display-name=togglebit;emotes=;flags=;id=4c4205a5-cce1-497f-8ea7-aed18a3b113e;mod=0;room-id=474725923;subscriber=1;tmi-sent-ts=1632729819621;turbo=0;user-id=474725923;user-type= :togglebit!togglebit@togglebit.tmi.twitch.tv PRIVMSG #togglebit :\u{1}ACTION test\u{1}\r\n";
         let msg = parse(input).unwrap();
-        assert!(matches!(msg, Irc::Message(IrcMessage { user: Cow::Borrowed("togglebit"), action: true, .. })));
+        assert!(matches!(msg, Irc::Message(IrcMessage { ref nick, action: true, .. }) if nick == "togglebit"));
+    }
+
+    #[test]
+    fn parse_login_failed() {
+        let input = ":tmi.twitch.tv NOTICE * :Login authentication failed\r\n";
+        let msg = parse(input).unwrap();
+        assert_eq!(msg_notice(msg), Notice {
+            channel: None,
+            kind: NoticeKind::LoginFailed,
+            message: "Login authentication failed".into(),
+        });
+    }
+
+    #[test]
+    fn parse_notice_with_msg_id() {
+        let input = "@msg-id=slow_on :tmi.twitch.tv NOTICE #togglebit :This room is now in slow mode. You may send messages every 30 seconds.\r\n";
+        let notice = msg_notice(parse(input).unwrap());
+        assert_eq!(notice.channel.as_deref(), Some("togglebit"));
+        assert_eq!(notice.kind, NoticeKind::SlowOn);
+    }
+
+    fn msg_notice(msg: Irc) -> Notice {
+        match msg {
+            Irc::Notice(notice) => notice,
+            _ => panic!("Incorrect message type")
+        }
+    }
+
+    fn message(input: &str) -> IrcMessage {
+        match parse(input).unwrap() {
+            Irc::Message(msg) => msg,
+            _ => panic!("Incorrect message type")
+        }
+    }
+
+    fn user_notice(input: &str) -> UserNotice {
+        match parse(input).unwrap() {
+            Irc::UserNotice(notice) => notice,
+            _ => panic!("Incorrect message type")
+        }
+    }
+
+    fn user_state(input: &str) -> UserState {
+        match parse(input).unwrap() {
+            Irc::UserState(state) => state,
+            _ => panic!("Incorrect message type")
+        }
+    }
+
+    fn room_state(input: &str) -> RoomState {
+        match parse(input).unwrap() {
+            Irc::RoomState(state) => state,
+            _ => panic!("Incorrect message type")
+        }
+    }
+
+    #[test]
+    fn parse_global_user_state() {
+        let input = "@badge-info=;badges=;color=#5F9EA0;display-name=NeoBot;emote-sets=0,300374282;user-id=474725923;user-type= :tmi.twitch.tv GLOBALUSERSTATE\r\n";
+        let state = match parse(input).unwrap() {
+            Irc::GlobalUserState(state) => state,
+            _ => panic!("Incorrect message type")
+        };
+        assert_eq!(state.channel, None);
+        assert_eq!(state.user_id.as_deref(), Some("474725923"));
+        assert_eq!(state.display_name, "NeoBot");
+        assert_eq!(state.emote_sets, vec!["0", "300374282"]);
+    }
+
+    #[test]
+    fn parse_user_state() {
+        let input = "@badge-info=;badges=moderator/1;color=;display-name=NeoBot;emote-sets=0;mod=1;subscriber=0;user-type=mod :tmi.twitch.tv USERSTATE #togglebit\r\n";
+        let state = user_state(input);
+        assert_eq!(state.channel.as_deref(), Some("togglebit"));
+        assert_eq!(state.color, None);
+        assert!(state.moderator);
+        assert!(state.has_badge("moderator"));
+        assert!(!state.is_broadcaster());
+    }
+
+    #[test]
+    fn parse_room_state() {
+        let input = "@emote-only=0;followers-only=-1;r9k=0;room-id=474725923;slow=0;subs-only=0 :tmi.twitch.tv ROOMSTATE #togglebit\r\n";
+        let state = room_state(input);
+        assert_eq!(state.channel, "togglebit");
+        assert_eq!(state.followers_only, Some(-1));
+        assert_eq!(state.emote_only, Some(false));
+        assert!(!state.is_followers_only());
+
+        // Partial update
+        let input = "@room-id=474725923;slow=10 :tmi.twitch.tv ROOMSTATE #togglebit\r\n";
+        let update = room_state(input);
+        assert_eq!(update.emote_only, None);
+
+        let mut merged = state.clone();
+        merged.merge(update);
+        assert_eq!(merged.slow, Some(10));
+        assert_eq!(merged.emote_only, Some(false));
+        assert!(merged.is_slow());
+    }
+
+    #[test]
+    fn parse_resub() {
+        let input = "@badge-info=subscriber/8;badges=subscriber/6;color=#1E90FF;display-name=randomuser;emotes=;flags=;id=5f2c7a1e-6b2f-4a4e-a2c4-1f06a0f0b5d1;login=randomuser;mod=0;msg-id=resub;msg-param-cumulative-months=8;msg-param-months=0;msg-param-multimonth-duration=0;msg-param-multimonth-tenure=0;msg-param-should-share-streak=1;msg-param-streak-months=3;msg-param-sub-plan-name=Channel\\sSubscription;msg-param-sub-plan=1000;msg-param-was-gifted=false;room-id=474725923;subscriber=1;system-msg=randomuser\\ssubscribed\\sat\\sTier\\s1.;tmi-sent-ts=1632681408560;user-id=12345;user-type= :tmi.twitch.tv USERNOTICE #togglebit :eight months!\r\n";
+        let notice = user_notice(input);
+        assert_eq!(notice.login, "randomuser");
+        assert_eq!(notice.channel, "togglebit");
+        assert_eq!(notice.message.as_deref(), Some("eight months!"));
+        assert_eq!(notice.system_msg, "randomuser subscribed at Tier 1.");
+        match notice.kind {
+            UserNoticeKind::Resub(info) => {
+                assert_eq!(info.cumulative_months, 8);
+                assert_eq!(info.streak_months, Some(3));
+                assert_eq!(info.sub_plan, SubPlan::Tier1);
+                assert!(!info.was_gifted);
+            }
+            kind => panic!("Incorrect notice kind: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn parse_raid_without_message() {
+        let input = "@badge-info=;badges=;color=;display-name=Raider;emotes=;flags=;id=3d830f12-795c-447d-af3c-ea05e40fbddb;login=raider;mod=0;msg-id=raid;msg-param-displayName=Raider;msg-param-login=raider;msg-param-viewerCount=15;room-id=474725923;subscriber=0;system-msg=15\\sraiders\\sfrom\\sRaider\\shave\\sjoined!;tmi-sent-ts=1632681408560;user-id=123;user-type= :tmi.twitch.tv USERNOTICE #togglebit\r\n";
+        let notice = user_notice(input);
+        assert_eq!(notice.channel, "togglebit");
+        assert!(notice.message.is_none());
+        assert_eq!(notice.kind, UserNoticeKind::Raid {
+            display_name: "Raider".into(),
+            login: "raider".into(),
+            viewer_count: 15,
+        });
+    }
+
+    #[test]
+    fn parse_unknown_user_notice() {
+        let input = "@login=randomuser;msg-id=somethingnew;room-id=474725923;system-msg= :tmi.twitch.tv USERNOTICE #togglebit\r\n";
+        let msg = parse(input).unwrap();
+        assert!(matches!(msg, Irc::UserNotice(UserNotice { kind: UserNoticeKind::Unknown { .. }, .. })));
     }
 }
@@ -0,0 +1,117 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use neotwitch::{Irc, IrcMessage, RoomState, UserState};
+
+// Number of Shared Chat message ids to remember.
+// Copies of a message arrive close together, so this doesn't need to be big.
+const MAX_SEEN_SHARED_MESSAGES: usize = 500;
+
+// -----------------------------------------------------------------------------
+//     - Chat state -
+//     Anything the chat agent needs to remember between messages
+// -----------------------------------------------------------------------------
+#[derive(Default)]
+pub struct ChatState {
+    room_states: HashMap<String, RoomState>,
+    global_user_state: Option<UserState>,
+    user_states: HashMap<String, UserState>,
+    chatters: HashMap<String, HashSet<String>>,
+    seen_shared_messages: HashSet<String>,
+    seen_shared_order: VecDeque<String>,
+}
+
+impl ChatState {
+    /// Track the state from an incoming message.
+    /// Room state updates are partial, so they are replaced with the
+    /// merged state of the channel before being passed on.
+    pub fn update(&mut self, irc: Irc) -> Irc {
+        match irc {
+            Irc::RoomState(update) => {
+                let state = self
+                    .room_states
+                    .entry(update.channel.clone())
+                    .or_insert_with(|| RoomState { channel: update.channel.clone(), ..Default::default() });
+                state.merge(update);
+                Irc::RoomState(state.clone())
+            }
+            Irc::GlobalUserState(state) => {
+                self.global_user_state = Some(state.clone());
+                Irc::GlobalUserState(state)
+            }
+            Irc::UserState(state) => {
+                if let Some(channel) = &state.channel {
+                    self.user_states.insert(channel.clone(), state.clone());
+                }
+                Irc::UserState(state)
+            }
+            Irc::Join { channel, login } => {
+                self.chatters.entry(channel.clone()).or_default().insert(login.clone());
+                Irc::Join { channel, login }
+            }
+            Irc::Part { channel, login } => {
+                if let Some(chatters) = self.chatters.get_mut(&channel) {
+                    chatters.remove(&login);
+                }
+                Irc::Part { channel, login }
+            }
+            Irc::Names { channel, logins } => {
+                self.chatters.entry(channel.clone()).or_default().extend(logins.iter().cloned());
+                Irc::Names { channel, logins }
+            }
+            irc => irc,
+        }
+    }
+
+    /// True if this Shared Chat message was already seen in another joined channel
+    pub fn is_shared_duplicate(&mut self, irc: &Irc) -> bool {
+        let message_id = match irc {
+            Irc::Message(IrcMessage { source: Some(source), .. }) => &source.message_id,
+            _ => return false,
+        };
+
+        if !self.seen_shared_messages.insert(message_id.clone()) {
+            return true;
+        }
+
+        self.seen_shared_order.push_back(message_id.clone());
+        if self.seen_shared_order.len() > MAX_SEEN_SHARED_MESSAGES {
+            if let Some(oldest) = self.seen_shared_order.pop_front() {
+                self.seen_shared_messages.remove(&oldest);
+            }
+        }
+
+        false
+    }
+
+    pub fn room_states(&self) -> impl Iterator<Item = &RoomState> {
+        self.room_states.values()
+    }
+
+    /// Chatters in each channel, one message per channel
+    pub fn chatters(&self) -> impl Iterator<Item = Irc> + '_ {
+        self.chatters.iter().map(|(channel, chatters)| Irc::Chatters {
+            channel: channel.clone(),
+            chatters: chatters.iter().cloned().collect(),
+        })
+    }
+
+    /// Forget all chatters.
+    /// Twitch sends the full list again when joining a channel.
+    pub fn clear_chatters(&mut self) {
+        self.chatters.clear();
+    }
+
+    /// Forget everything about a channel after leaving it
+    pub fn remove_channel(&mut self, channel: &str) {
+        self.room_states.remove(channel);
+        self.user_states.remove(channel);
+        self.chatters.remove(channel);
+    }
+
+    /// The global user state followed by the user state of each channel
+    pub fn user_states(&self) -> impl Iterator<Item = Irc> + '_ {
+        let global = self.global_user_state.clone().map(Irc::GlobalUserState);
+        let channels = self.user_states.values().cloned().map(Irc::UserState);
+        global.into_iter().chain(channels)
+    }
+}
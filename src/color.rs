@@ -0,0 +1,175 @@
+// -----------------------------------------------------------------------------
+//     - Default colours -
+// -----------------------------------------------------------------------------
+/// The colours Twitch picks from for users who never chose a colour
+pub const DEFAULT_COLORS: [&str; 15] = [
+    "#FF0000", "#0000FF", "#00FF00", "#B22222", "#FF7F50", "#9ACD32", "#FF4500", "#2E8B57", "#DAA520", "#D2691E",
+    "#5F9EA0", "#1E90FF", "#FF69B4", "#8A2BE2", "#00FF7F",
+];
+
+/// The default colour for a login.
+/// Twitch picks it from the first and last character of the name,
+/// so the same user always gets the same colour.
+pub fn default_color(login: &str) -> &'static str {
+    let first = login.chars().next().map(|c| c as usize).unwrap_or(0);
+    let last = login.chars().last().map(|c| c as usize).unwrap_or(0);
+    DEFAULT_COLORS[(first + last) % DEFAULT_COLORS.len()]
+}
+
+// -----------------------------------------------------------------------------
+//     - Readability -
+// -----------------------------------------------------------------------------
+// WCAG recommends at least 4.5 for text
+const MIN_CONTRAST: f64 = 4.5;
+const LIGHTNESS_STEP: f64 = 0.05;
+
+/// Lighten or darken a colour until it's readable on the background.
+/// Both colours are hex strings (`#RRGGBB`).
+/// Returns `None` if either of them is not a valid colour.
+pub fn readable_color(color: &str, background: &str) -> Option<String> {
+    let color = Rgb::from_hex(color)?;
+    let background = Rgb::from_hex(background)?;
+
+    let dark_background = background.luminance() < 0.5;
+    let (hue, saturation, mut lightness) = color.to_hsl();
+    let mut adjusted = color;
+
+    while adjusted.contrast(&background) < MIN_CONTRAST {
+        lightness = match dark_background {
+            true => lightness + LIGHTNESS_STEP,
+            false => lightness - LIGHTNESS_STEP,
+        };
+
+        if !(0.0..=1.0).contains(&lightness) {
+            break;
+        }
+
+        adjusted = Rgb::from_hsl(hue, saturation, lightness);
+    }
+
+    Some(adjusted.to_hex())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Rgb {
+    r: f64,
+    g: f64,
+    b: f64,
+}
+
+impl Rgb {
+    fn from_hex(hex: &str) -> Option<Self> {
+        let hex = hex.strip_prefix('#').unwrap_or(hex);
+        if hex.len() != 6 || !hex.is_ascii() {
+            return None;
+        }
+
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok().map(|c| c as f64 / 255.0);
+        Some(Self { r: channel(0)?, g: channel(2)?, b: channel(4)? })
+    }
+
+    fn to_hex(self) -> String {
+        let channel = |c: f64| (c.clamp(0.0, 1.0) * 255.0).round() as u8;
+        format!("#{:02X}{:02X}{:02X}", channel(self.r), channel(self.g), channel(self.b))
+    }
+
+    // Relative luminance as defined by WCAG
+    fn luminance(&self) -> f64 {
+        let linear = |c: f64| match c <= 0.03928 {
+            true => c / 12.92,
+            false => ((c + 0.055) / 1.055).powf(2.4),
+        };
+
+        0.2126 * linear(self.r) + 0.7152 * linear(self.g) + 0.0722 * linear(self.b)
+    }
+
+    fn contrast(&self, other: &Self) -> f64 {
+        let (a, b) = (self.luminance(), other.luminance());
+        (a.max(b) + 0.05) / (a.min(b) + 0.05)
+    }
+
+    fn to_hsl(self) -> (f64, f64, f64) {
+        let max = self.r.max(self.g).max(self.b);
+        let min = self.r.min(self.g).min(self.b);
+        let lightness = (max + min) / 2.0;
+
+        if max == min {
+            return (0.0, 0.0, lightness);
+        }
+
+        let delta = max - min;
+        let saturation = match lightness > 0.5 {
+            true => delta / (2.0 - max - min),
+            false => delta / (max + min),
+        };
+
+        let hue = if max == self.r {
+            (self.g - self.b) / delta + if self.g < self.b { 6.0 } else { 0.0 }
+        } else if max == self.g {
+            (self.b - self.r) / delta + 2.0
+        } else {
+            (self.r - self.g) / delta + 4.0
+        };
+
+        (hue / 6.0, saturation, lightness)
+    }
+
+    fn from_hsl(hue: f64, saturation: f64, lightness: f64) -> Self {
+        if saturation == 0.0 {
+            return Self { r: lightness, g: lightness, b: lightness };
+        }
+
+        let q = match lightness < 0.5 {
+            true => lightness * (1.0 + saturation),
+            false => lightness + saturation - lightness * saturation,
+        };
+        let p = 2.0 * lightness - q;
+
+        let channel = |t: f64| {
+            let t = t.rem_euclid(1.0);
+            if t < 1.0 / 6.0 {
+                p + (q - p) * 6.0 * t
+            } else if t < 0.5 {
+                q
+            } else if t < 2.0 / 3.0 {
+                p + (q - p) * (2.0 / 3.0 - t) * 6.0
+            } else {
+                p
+            }
+        };
+
+        Self {
+            r: channel(hue + 1.0 / 3.0),
+            g: channel(hue),
+            b: channel(hue - 1.0 / 3.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn default_color_is_stable() {
+        assert_eq!(default_color("togglebit"), default_color("togglebit"));
+        // 't' (116) + 't' (116) = 232 % 15 = 7
+        assert_eq!(default_color("togglebit"), "#2E8B57");
+        assert_eq!(default_color(""), DEFAULT_COLORS[0]);
+    }
+
+    #[test]
+    fn readable_on_dark_and_light() {
+        let blue_on_black = readable_color("#0000FF", "#000000").unwrap();
+        let blue = Rgb::from_hex(&blue_on_black).unwrap();
+        assert!(blue.contrast(&Rgb::from_hex("#000000").unwrap()) >= MIN_CONTRAST);
+
+        let green_on_white = readable_color("#00FF00", "#FFFFFF").unwrap();
+        let green = Rgb::from_hex(&green_on_white).unwrap();
+        assert!(green.contrast(&Rgb::from_hex("#FFFFFF").unwrap()) >= MIN_CONTRAST);
+
+        // Already readable, so unchanged
+        assert_eq!(readable_color("#FFFFFF", "#000000").unwrap(), "#FFFFFF");
+        assert!(readable_color("nope", "#000000").is_none());
+    }
+}
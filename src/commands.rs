@@ -0,0 +1,224 @@
+use serde::{Deserialize, Serialize};
+
+use crate::IrcMessage;
+
+// -----------------------------------------------------------------------------
+//     - Permission -
+// -----------------------------------------------------------------------------
+/// Who can use a command, from the badges of the user.
+/// Each level includes the ones above it, e.g a moderator can use `Vip` commands.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    #[default]
+    Everyone,
+    Subscriber,
+    Vip,
+    Moderator,
+    Broadcaster,
+}
+
+impl Permission {
+    /// The highest permission level of the user who sent the message
+    pub fn of(msg: &IrcMessage) -> Self {
+        if msg.is_broadcaster() {
+            Self::Broadcaster
+        } else if msg.is_moderator() {
+            Self::Moderator
+        } else if msg.is_vip() {
+            Self::Vip
+        } else if msg.is_subscriber() {
+            Self::Subscriber
+        } else {
+            Self::Everyone
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Command spec -
+// -----------------------------------------------------------------------------
+/// A chat command, e.g `!hello`.
+/// Registered through the config or by a client with `command {...}`.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct CommandSpec {
+    /// Name without the prefix
+    pub name: String,
+    #[serde(default)]
+    pub permission: Permission,
+    /// Seconds between uses in a channel
+    #[serde(default)]
+    pub cooldown: u64,
+    /// Seconds between uses by the same user in a channel
+    #[serde(default)]
+    pub user_cooldown: u64,
+    /// Template to reply with, see `render`.
+    /// Commands registered by clients usually leave this out and reply themselves.
+    #[serde(default)]
+    pub response: Option<String>,
+}
+
+// -----------------------------------------------------------------------------
+//     - Invocation -
+// -----------------------------------------------------------------------------
+/// Someone used a command in chat
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct CommandInvocation {
+    /// Lowercase name without the prefix
+    pub name: String,
+    pub args: Vec<String>,
+    pub channel: String,
+    pub login: String,
+    pub display_name: String,
+    pub permission: Permission,
+    /// Id of the message, to reply to it
+    pub message_id: Option<String>,
+}
+
+impl CommandInvocation {
+    /// Parse a command like `!so togglebit` from a message.
+    /// The `@login` Twitch adds to replies is ignored.
+    pub fn parse(msg: &IrcMessage, prefix: &str) -> Option<Self> {
+        let text = msg.message_without_reply_mention().strip_prefix(prefix)?;
+        let (name, args) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        if name.is_empty() {
+            return None;
+        }
+
+        let inst = Self {
+            name: name.to_lowercase(),
+            args: tokenize(args),
+            channel: msg.channel.clone(),
+            login: msg.user.login.clone(),
+            display_name: msg.user.display_name.clone(),
+            permission: Permission::of(msg),
+            message_id: msg.tags.get("id").cloned(),
+        };
+
+        Some(inst)
+    }
+}
+
+/// Sent to the client that registered a command
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub enum CommandEvent {
+    Invoked(CommandInvocation),
+}
+
+/// Split command arguments on whitespace.
+/// Double quotes keep an argument together, e.g `"hello world"`.
+pub fn tokenize(args: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut quoted = false;
+    let mut in_token = false;
+
+    for c in args.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_token = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_token {
+                    tokens.push(std::mem::take(&mut token));
+                    in_token = false;
+                }
+            }
+            c => {
+                token.push(c);
+                in_token = true;
+            }
+        }
+    }
+
+    if in_token {
+        tokens.push(token);
+    }
+
+    tokens
+}
+
+/// Fill in a response template.
+///
+/// * `{user}`: display name of the user
+/// * `{login}`: login of the user
+/// * `{channel}`: channel name
+/// * `{args}`: all the arguments
+/// * `{1}`, `{2}`, ...: a single argument, empty if it's missing
+///
+/// Anything else in braces is left as is.
+pub fn render(template: &str, invocation: &CommandInvocation) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let end = match rest.find('}') {
+            Some(end) => end,
+            None => break,
+        };
+
+        let placeholder = &rest[1..end];
+        match placeholder {
+            "user" => output.push_str(&invocation.display_name),
+            "login" => output.push_str(&invocation.login),
+            "channel" => output.push_str(&invocation.channel),
+            "args" => output.push_str(&invocation.args.join(" ")),
+            _ => match placeholder.parse::<usize>() {
+                Ok(index) if index > 0 => {
+                    output.push_str(invocation.args.get(index - 1).map(String::as_str).unwrap_or_default())
+                }
+                _ => output.push_str(&rest[..=end]),
+            },
+        }
+
+        rest = &rest[end + 1..];
+    }
+
+    output.push_str(rest);
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn invocation(args: &[&str]) -> CommandInvocation {
+        CommandInvocation {
+            name: "so".into(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+            channel: "togglebit".into(),
+            login: "randomuser".into(),
+            display_name: "RandomUser".into(),
+            permission: Permission::Everyone,
+            message_id: None,
+        }
+    }
+
+    #[test]
+    fn tokenize_quotes() {
+        assert_eq!(tokenize("  a  b "), vec!["a", "b"]);
+        assert_eq!(tokenize(r#"add "hello world" x"#), vec!["add", "hello world", "x"]);
+        assert_eq!(tokenize(r#"empty "" "unterminated quote"#), vec!["empty", "", "unterminated quote"]);
+        assert!(tokenize("").is_empty());
+    }
+
+    #[test]
+    fn render_template() {
+        let inv = invocation(&["togglebit"]);
+        assert_eq!(render("Go follow {1}, says {user}!", &inv), "Go follow togglebit, says RandomUser!");
+        assert_eq!(render("{2}|{0}|{nope}|{args", &inv), "|{0}|{nope}|{args");
+        assert_eq!(render("#{channel} {login}: {args}", &inv), "#togglebit randomuser: togglebit");
+    }
+
+    #[test]
+    fn permission_order() {
+        assert!(Permission::Broadcaster > Permission::Moderator);
+        assert!(Permission::Moderator > Permission::Vip);
+        assert!(Permission::Vip > Permission::Subscriber);
+        assert!(Permission::Subscriber > Permission::Everyone);
+    }
+}
@@ -1,19 +1,59 @@
-use std::env;
+use std::{env, fs};
 use anyhow::{anyhow, Result};
+use neotwitch::CommandSpec;
+use rand::prelude::*;
 
 const NEO_TWITCH_TOKEN: &str = "NEO_TWITCH_TOKEN";
 const NEO_TWITCH_CHANNEL: &str = "NEO_TWITCH_CHANNEL";
 const NEO_TWITCH_IRC_NICK: &str = "NEO_TWITCH_IRC_NICK";
 const NEO_TWITCH_IRC_CHANNELS: &str = "NEO_TWITCH_IRC_CHANNELS";
+const NEO_TWITCH_IRC_MEMBERSHIP: &str = "NEO_TWITCH_IRC_MEMBERSHIP";
+const NEO_TWITCH_IRC_DEDUPE_SHARED_CHAT: &str = "NEO_TWITCH_IRC_DEDUPE_SHARED_CHAT";
+const NEO_TWITCH_IRC_OVERFLOW: &str = "NEO_TWITCH_IRC_OVERFLOW";
+const NEO_TWITCH_IRC_QUEUE_SIZE: &str = "NEO_TWITCH_IRC_QUEUE_SIZE";
+const NEO_TWITCH_COMMANDS: &str = "NEO_TWITCH_COMMANDS";
+const NEO_TWITCH_COMMAND_PREFIX: &str = "NEO_TWITCH_COMMAND_PREFIX";
+
+const DEFAULT_QUEUE_SIZE: usize = 50;
+
+/// What to do with an outgoing chat message when the queue of the channel is full
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    /// Drop the oldest queued message to make room
+    Drop,
+    /// Refuse the new message
+    Reject,
+    /// Keep one message per client waiting past the end of the queue,
+    /// so a client that waits for each acknowledgement is slowed down rather than refused.
+    /// Any more from the same client are refused.
+    Delay,
+}
 
 pub struct Config {
+    /// Empty when anonymous
     pub channel_id: String,
+    /// Empty when anonymous
     pub token: String,
     pub nick: String,
+    /// No credentials, so chat is read as `justinfanNNNN`.
+    /// PubSub and sending messages are turned off.
+    pub anonymous: bool,
     pub irc_channels: Vec<String>,
+    /// Request JOIN, PART and NAMES messages from Twitch
+    pub membership: bool,
+    /// Only pass on the first copy of a Shared Chat message
+    /// when more than one channel in the session is joined
+    pub dedupe_shared_chat: bool,
+    pub overflow_policy: OverflowPolicy,
+    /// Max number of outgoing messages queued per channel
+    pub queue_size: usize,
+    /// Chat commands, from a JSON file with a list of commands
+    pub commands: Vec<CommandSpec>,
+    pub command_prefix: String,
 }
 
-fn validate_channel(channel: &str) -> Option<String> {
+/// A description of what's wrong with the channel name, if anything
+pub fn validate_channel(channel: &str) -> Option<String> {
     if channel.is_empty() {
         return Some("Channel name can not be empty".into());
     }
@@ -26,19 +66,73 @@ fn validate_channel(channel: &str) -> Option<String> {
         return Some("Channel name has to start with a # char, e.g #mychannel".into());
     }
 
+    // Several channels are separated by spaces or commas
+    if channel.contains(|c: char| c.is_whitespace() || c == ',') {
+        return Some("Channel name can not contain spaces or commas".into());
+    }
+
     if channel.len() > 200 {
         return Some("Channel name can not be longer than 200 characters".into());
     }
 
+    // Anything else (line breaks, colons) would let the channel add to the line
+    if !channel[1..].chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Some(format!("Invalid channel name \"{}\"", channel));
+    }
+
     None
 }
 
+// The token and the nick, and if chat is read anonymously.
+// Without either, chat is read anonymously. With only one of them
+// it's more likely a mistake, so that's an error.
+fn credentials(token: Option<String>, nick: Option<String>) -> Result<(String, String, bool)> {
+    match (token, nick) {
+        (Some(token), Some(nick)) => Ok((token, nick, false)),
+        (None, None) => Ok((String::new(), format!("justinfan{}", thread_rng().gen_range(1000..100_000)), true)),
+        (Some(_), None) => Err(anyhow!("{} is missing, it's required with {}", NEO_TWITCH_IRC_NICK, NEO_TWITCH_TOKEN)),
+        (None, Some(_)) => Err(anyhow!("{} is missing, it's required with {}", NEO_TWITCH_TOKEN, NEO_TWITCH_IRC_NICK)),
+    }
+}
+
+// An empty queue would refuse every message
+fn queue_size(size: Option<String>) -> Result<usize> {
+    match size {
+        Some(size) => match size.parse() {
+            Ok(0) | Err(_) => Err(anyhow!("Invalid queue size \"{}\", expected a number above 0", size)),
+            Ok(size) => Ok(size),
+        },
+        None => Ok(DEFAULT_QUEUE_SIZE),
+    }
+}
+
 impl Config {
     pub fn new() -> Result<Self> {
-        let channel_id = env::var(NEO_TWITCH_CHANNEL).map_err(|_| anyhow!("Channel id missing"))?;
-        let token = env::var(NEO_TWITCH_TOKEN).map_err(|_| anyhow!("OAuth token missing"))?;
-        let nick = env::var(NEO_TWITCH_IRC_NICK).map_err(|_| anyhow!("Nick is missing"))?;
+        let (token, nick, anonymous) = credentials(env::var(NEO_TWITCH_TOKEN).ok(), env::var(NEO_TWITCH_IRC_NICK).ok())?;
+        let channel_id = match anonymous {
+            false => env::var(NEO_TWITCH_CHANNEL).map_err(|_| anyhow!("Channel id missing"))?,
+            true => String::new(),
+        };
         let irc_channels = env::var(NEO_TWITCH_IRC_CHANNELS).map_err(|_| anyhow!("Channels are missing"))?;
+        let membership = env::var(NEO_TWITCH_IRC_MEMBERSHIP).map(|m| m == "1" || m == "true").unwrap_or(false);
+        let dedupe_shared_chat = env::var(NEO_TWITCH_IRC_DEDUPE_SHARED_CHAT).map(|d| d == "1" || d == "true").unwrap_or(false);
+
+        let overflow_policy = match env::var(NEO_TWITCH_IRC_OVERFLOW).as_deref() {
+            Ok("drop") => OverflowPolicy::Drop,
+            Ok("reject") => OverflowPolicy::Reject,
+            Ok("delay") | Err(_) => OverflowPolicy::Delay,
+            Ok(policy) => return Err(anyhow!("Invalid overflow policy \"{}\", expected drop, reject or delay", policy)),
+        };
+        let queue_size = queue_size(env::var(NEO_TWITCH_IRC_QUEUE_SIZE).ok())?;
+
+        let commands = match env::var(NEO_TWITCH_COMMANDS) {
+            Ok(path) => {
+                let json = fs::read_to_string(&path).map_err(|e| anyhow!("Failed to read commands \"{}\": {}", path, e))?;
+                serde_json::from_str(&json).map_err(|e| anyhow!("Invalid commands \"{}\": {}", path, e))?
+            }
+            Err(_) => Vec::new(),
+        };
+        let command_prefix = env::var(NEO_TWITCH_COMMAND_PREFIX).unwrap_or_else(|_| "!".into());
 
         let irc_channels = irc_channels
             .split(&[' ', ','][..])
@@ -57,9 +151,64 @@ impl Config {
             channel_id,
             token,
             nick,
+            anonymous,
             irc_channels,
+            membership,
+            dedupe_shared_chat,
+            overflow_policy,
+            queue_size,
+            commands,
+            command_prefix,
         };
 
         Ok(inst)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn logged_in() {
+        let (token, nick, anonymous) = credentials(Some("token".into()), Some("neobot".into())).unwrap();
+        assert_eq!(token, "token");
+        assert_eq!(nick, "neobot");
+        assert!(!anonymous);
+    }
+
+    #[test]
+    fn anonymous() {
+        let (token, nick, anonymous) = credentials(None, None).unwrap();
+        assert!(token.is_empty());
+        assert!(nick.starts_with("justinfan"));
+        assert!(anonymous);
+    }
+
+    #[test]
+    fn missing_credential() {
+        let err = credentials(Some("token".into()), None).unwrap_err();
+        assert!(err.to_string().starts_with(NEO_TWITCH_IRC_NICK));
+
+        let err = credentials(None, Some("neobot".into())).unwrap_err();
+        assert!(err.to_string().starts_with(NEO_TWITCH_TOKEN));
+    }
+
+    #[test]
+    fn queue_sizes() {
+        assert_eq!(queue_size(None).unwrap(), DEFAULT_QUEUE_SIZE);
+        assert_eq!(queue_size(Some("10".into())).unwrap(), 10);
+        assert!(queue_size(Some("0".into())).is_err());
+        assert!(queue_size(Some("ten".into())).is_err());
+    }
+
+    #[test]
+    fn channel_names() {
+        assert!(validate_channel("#togglebit").is_none());
+        assert!(validate_channel("#Toggle_Bit99").is_none());
+        assert!(validate_channel("togglebit").is_some());
+        assert!(validate_channel("#foo-bar").is_some());
+        assert!(validate_channel("#a:b").is_some());
+        assert!(validate_channel("#a\r\nJOIN").is_some());
+    }
+}
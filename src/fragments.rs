@@ -0,0 +1,311 @@
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+// -----------------------------------------------------------------------------
+//     - Fragment -
+// -----------------------------------------------------------------------------
+/// Part of a chat message
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Fragment {
+    Text(String),
+    Emote {
+        id: String,
+        name: String,
+    },
+    Cheermote(Cheermote),
+    /// `@login`, without the `@`
+    Mention {
+        login: String,
+    },
+    Link {
+        url: String,
+    },
+}
+
+// -----------------------------------------------------------------------------
+//     - Cheermote -
+// -----------------------------------------------------------------------------
+/// Bits cheered in a message, e.g `Cheer100` or `PogChamp500`
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct Cheermote {
+    pub prefix: String,
+    pub amount: usize,
+    /// The tier decides the image of the cheermote: 1, 100, 1000, 5000 or 10000
+    pub tier: usize,
+}
+
+const CHEERMOTE_TIERS: [usize; 5] = [10_000, 5_000, 1_000, 100, 1];
+
+impl Cheermote {
+    /// A cheermote is a prefix followed by a number
+    pub fn parse(word: &str) -> Option<Self> {
+        let pos = word.find(|c: char| c.is_ascii_digit())?;
+        let (prefix, amount) = word.split_at(pos);
+        if prefix.is_empty() || !prefix.chars().all(char::is_alphabetic) {
+            return None;
+        }
+
+        let amount = amount.parse().ok().filter(|amount| *amount > 0)?;
+        let tier = CHEERMOTE_TIERS.into_iter().find(|tier| amount >= *tier)?;
+
+        Some(Self { prefix: prefix.into(), amount, tier })
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Emote range -
+// -----------------------------------------------------------------------------
+/// Position of an emote in a message.
+/// `start` and `end` (inclusive) count unicode code points, not bytes.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct EmoteRange {
+    pub id: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl EmoteRange {
+    /// Parse the `emotes` tag, e.g `25:0-4,12-16/1902:6-10`.
+    /// The ranges are sorted by their start position.
+    pub fn parse_list(emotes: &str) -> Vec<Self> {
+        let mut ranges = emotes
+            .split('/')
+            .filter_map(|emote| emote.split_once(':'))
+            .flat_map(|(id, positions)| {
+                positions.split(',').filter_map(move |pos| {
+                    let (start, end) = pos.split_once('-')?;
+                    let range = Self {
+                        id: id.into(),
+                        start: start.parse().ok()?,
+                        end: end.parse().ok()?,
+                    };
+                    Some(range)
+                })
+            })
+            .filter(|range| range.start <= range.end)
+            .collect::<Vec<_>>();
+
+        ranges.sort_by_key(|range| range.start);
+        ranges
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Mentions and links -
+// -----------------------------------------------------------------------------
+/// `@login` in a message.
+/// The span is the byte range of the mention (including the `@`) in the message.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct Mention {
+    /// Lowercase login, without the `@`
+    pub login: String,
+    pub span: Range<usize>,
+}
+
+/// A link in a message, as written (the scheme is optional).
+/// The span is the byte range of the url in the message.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct Link {
+    pub url: String,
+    pub span: Range<usize>,
+}
+
+// -----------------------------------------------------------------------------
+//     - Split message into fragments -
+// -----------------------------------------------------------------------------
+/// Split a message into fragments.
+/// Cheermotes are only considered if the message contained bits.
+pub fn fragments(message: &str, emotes: &[EmoteRange], has_bits: bool) -> Vec<Fragment> {
+    spanned_fragments(message, emotes, has_bits).into_iter().map(|(_, fragment)| fragment).collect()
+}
+
+/// Same as `fragments` but with the byte range of each fragment in the message
+pub fn spanned_fragments(message: &str, emotes: &[EmoteRange], has_bits: bool) -> Vec<(Range<usize>, Fragment)> {
+    // Emote positions are in code points, so keep the byte offset of each one
+    let offsets = message.char_indices().map(|(i, _)| i).chain(Some(message.len())).collect::<Vec<_>>();
+    let char_count = offsets.len() - 1;
+
+    let mut fragments = Vec::new();
+    let mut char_pos = 0;
+
+    for emote in emotes {
+        // Skip overlapping or out of bounds ranges rather than panic
+        if emote.start < char_pos || emote.end >= char_count {
+            continue;
+        }
+
+        let (start, end) = (offsets[emote.start], offsets[emote.end + 1]);
+        split_text(message, offsets[char_pos]..start, has_bits, &mut fragments);
+
+        let name = message[start..end].into();
+        fragments.push((start..end, Fragment::Emote { id: emote.id.clone(), name }));
+        char_pos = emote.end + 1;
+    }
+
+    split_text(message, offsets[char_pos]..message.len(), has_bits, &mut fragments);
+
+    fragments
+}
+
+// Find mentions, links and cheermotes in text between emotes
+fn split_text(message: &str, range: Range<usize>, has_bits: bool, fragments: &mut Vec<(Range<usize>, Fragment)>) {
+    let mut start = range.start;
+
+    for (word_start, word) in words(&message[range.clone()]) {
+        let word_start = range.start + word_start;
+
+        let (fragment, len) = if let Some(login) = word.strip_prefix('@') {
+            let login = login.trim_end_matches(|c: char| !(c.is_alphanumeric() || c == '_'));
+            if login.is_empty() {
+                continue;
+            }
+            (Fragment::Mention { login: login.to_lowercase() }, 1 + login.len())
+        } else if let Some(url) = link(word) {
+            (Fragment::Link { url: url.into() }, url.len())
+        } else if let Some(cheermote) = Cheermote::parse(word).filter(|_| has_bits) {
+            (Fragment::Cheermote(cheermote), word.len())
+        } else {
+            continue;
+        };
+
+        push_text(message, start..word_start, fragments);
+        fragments.push((word_start..word_start + len, fragment));
+        start = word_start + len;
+    }
+
+    push_text(message, start..range.end, fragments);
+}
+
+// Words and their byte offsets
+fn words(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.split(' ').scan(0, |offset, word| {
+        let start = *offset;
+        *offset += word.len() + 1;
+        Some((start, word))
+    })
+}
+
+// A word is a link if it has a scheme, or the host looks like a domain (e.g twitch.tv).
+// Trailing punctuation is not part of the link.
+fn link(word: &str) -> Option<&str> {
+    let url = word.trim_end_matches(['.', ',', '!', '?', ')', ':', ';', '"', '\'']);
+
+    let without_scheme = url.strip_prefix("https://").or_else(|| url.strip_prefix("http://"));
+    if let Some(rest) = without_scheme {
+        return match rest.is_empty() {
+            true => None,
+            false => Some(url),
+        };
+    }
+
+    let host = url.split(['/', '?', '#']).next()?;
+    let tld = host.rsplit('.').next()?;
+    let valid_labels = host.contains('.')
+        && host.split('.').all(|l| !l.is_empty() && l.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'));
+    let valid_tld = tld.len() >= 2 && tld.chars().all(|c| c.is_ascii_alphabetic());
+
+    match valid_labels && valid_tld {
+        true => Some(url),
+        false => None,
+    }
+}
+
+// Push text, merging it with the previous text fragment if any
+fn push_text(message: &str, range: Range<usize>, fragments: &mut Vec<(Range<usize>, Fragment)>) {
+    if range.is_empty() {
+        return;
+    }
+
+    match fragments.last_mut() {
+        Some((prev_range, Fragment::Text(prev))) if prev_range.end == range.start => {
+            prev.push_str(&message[range.clone()]);
+            prev_range.end = range.end;
+        }
+        _ => fragments.push((range.clone(), Fragment::Text(message[range].into()))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn emotes_use_code_points() {
+        let emotes = EmoteRange::parse_list("25:8-12/1902:0-4");
+        let frags = fragments("Keepo 🦀 Kappa!", &emotes, false);
+        assert_eq!(
+            frags,
+            vec![
+                Fragment::Emote { id: "1902".into(), name: "Keepo".into() },
+                Fragment::Text(" 🦀 ".into()),
+                Fragment::Emote { id: "25".into(), name: "Kappa".into() },
+                Fragment::Text("!".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn mentions_and_cheermotes() {
+        let frags = fragments("@togglebit, Cheer100 nice", &[], true);
+        assert_eq!(
+            frags,
+            vec![
+                Fragment::Mention { login: "togglebit".into() },
+                Fragment::Text(", ".into()),
+                Fragment::Cheermote(Cheermote { prefix: "Cheer".into(), amount: 100, tier: 100 }),
+                Fragment::Text(" nice".into()),
+            ]
+        );
+
+        // No bits, no cheermotes
+        let frags = fragments("Cheer100", &[], false);
+        assert_eq!(frags, vec![Fragment::Text("Cheer100".into())]);
+    }
+
+    #[test]
+    fn cheermote_tiers() {
+        let cheer = Cheermote::parse("PogChamp4999").unwrap();
+        assert_eq!(cheer.prefix, "PogChamp");
+        assert_eq!(cheer.amount, 4999);
+        assert_eq!(cheer.tier, 1000);
+
+        assert_eq!(Cheermote::parse("Cheer1").unwrap().tier, 1);
+        assert!(Cheermote::parse("Cheer0").is_none());
+        assert!(Cheermote::parse("100").is_none());
+        assert!(Cheermote::parse("Cheer10x").is_none());
+    }
+
+    #[test]
+    fn links_and_spans() {
+        let message = "🦀 see https://twitch.tv/togglebit, or github.com. e.g. 1.5 Kappa";
+        let emotes = EmoteRange::parse_list("25:59-63");
+        let frags = spanned_fragments(message, &emotes, false);
+
+        let links = frags
+            .iter()
+            .filter_map(|(span, frag)| match frag {
+                Fragment::Link { url } => Some((span.clone(), url.as_str())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(links.len(), 2);
+        assert_eq!(links[0].1, "https://twitch.tv/togglebit");
+        assert_eq!(&message[links[0].0.clone()], "https://twitch.tv/togglebit");
+        assert_eq!(links[1].1, "github.com");
+        assert_eq!(&message[links[1].0.clone()], "github.com");
+
+        let (span, emote) = frags.last().unwrap();
+        assert_eq!(emote, &Fragment::Emote { id: "25".into(), name: "Kappa".into() });
+        assert_eq!(&message[span.clone()], "Kappa");
+    }
+
+    #[test]
+    fn invalid_ranges_are_skipped() {
+        let emotes = EmoteRange::parse_list("25:0-40/26:a-b");
+        let frags = fragments("Kappa", &emotes, false);
+        assert_eq!(frags, vec![Fragment::Text("Kappa".into())]);
+    }
+}
@@ -1,17 +1,92 @@
 use std::collections::HashMap;
-use chrono::{DateTime, Local};
+use std::fmt;
+use chrono::{DateTime, Local, TimeZone};
 
 
 use serde::{Deserialize, Serialize};
 
+mod color;
+mod commands;
+mod fragments;
+mod raw;
+
+pub use color::{default_color, readable_color, DEFAULT_COLORS};
+pub use commands::{render, tokenize, CommandEvent, CommandInvocation, CommandSpec, Permission};
+pub use fragments::{Cheermote, EmoteRange, Fragment, Link, Mention};
+
 // -----------------------------------------------------------------------------
 //     - Irc -
 // -----------------------------------------------------------------------------
 /// Irc message
-#[derive(Debug, Deserialize, Serialize)]
+// Almost every message is a `Message`, so boxing it would only add an allocation per message
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
 pub enum Irc {
-    ClearChat,
-    Message(IrcMessage)
+    /// Chat was cleared, or a user was timed out / banned.
+    /// If `target_user` is `None` then all messages in the channel were removed.
+    ClearChat {
+        channel: String,
+        /// Login name of the user whose messages were removed
+        target_user: Option<String>,
+        target_user_id: Option<String>,
+        /// Duration of the timeout in seconds. `None` for a permanent ban
+        ban_duration: Option<u64>,
+    },
+    /// A single message was deleted
+    ClearMessage {
+        channel: String,
+        /// Login name of the user who sent the message
+        login: String,
+        /// The `id` tag of the deleted message
+        target_msg_id: String,
+    },
+    Message(IrcMessage),
+    UserNotice(UserNotice),
+    RoomState(RoomState),
+    Notice(Notice),
+    /// The bot's own state in a channel, sent on join and after sending a message
+    UserState(UserState),
+    /// The bot's own state, sent once after logging in
+    GlobalUserState(UserState),
+    /// A user joined the channel (requires the membership capability)
+    Join {
+        channel: String,
+        login: String,
+    },
+    /// A user left the channel (requires the membership capability)
+    Part {
+        channel: String,
+        login: String,
+    },
+    /// Users in the channel (NAMES reply, 353).
+    /// Sent in batches after joining a channel.
+    Names {
+        channel: String,
+        logins: Vec<String>,
+    },
+    /// End of the NAMES reply (366)
+    NamesEnd {
+        channel: String,
+    },
+    /// Everyone the chat agent knows is in the channel.
+    /// Only sent in reply to a `chatters` query, never by Twitch.
+    Chatters {
+        channel: String,
+        chatters: Vec<String>,
+    },
+    /// Twitch is about to restart the server and the connection will be closed.
+    /// The chat agent reconnects by itself when receiving this.
+    Reconnect,
+    /// Any command not covered above, e.g HOSTTARGET, WHISPER or numeric replies.
+    /// Only sent to subscribers who asked for raw messages.
+    Raw {
+        tags: HashMap<String, String>,
+        /// Prefix without the leading `:`, empty if there is no prefix
+        prefix: String,
+        command: String,
+        params: Vec<String>,
+        trailing: Option<String>,
+    },
 }
 
 // -----------------------------------------------------------------------------
@@ -20,14 +95,44 @@ pub enum Irc {
 /// Priv message (channel message)
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct IrcMessage {
+    /// When Twitch received the message (`tmi-sent-ts`),
+    /// or when it was received if the tag is missing
     pub timestamp: DateTime<Local>,
+    /// When the message was received
+    pub received: DateTime<Local>,
+    /// Login name of the sender, same as `user.login`
     pub nick: String,
+    pub user: ChatUser,
     pub channel: String,
     pub message: String,
     pub action: bool,
+    pub badges: Vec<Badge>,
+    pub badge_info: Vec<BadgeInfo>,
+    /// Set if the message is a reply to another message
+    pub reply: Option<ReplyContext>,
+    /// Set if the message was sent during a Shared Chat session
+    pub source: Option<SharedChatSource>,
     pub tags: HashMap<String, String>,
 }
 
+// The time the message was received is not part of the message itself,
+// and neither is the timestamp when it fell back to it.
+impl PartialEq for IrcMessage {
+    fn eq(&self, other: &Self) -> bool {
+        same_timestamp((&self.timestamp, &self.received), (&other.timestamp, &other.received))
+            && self.nick == other.nick
+            && self.user == other.user
+            && self.channel == other.channel
+            && self.message == other.message
+            && self.action == other.action
+            && self.badges == other.badges
+            && self.badge_info == other.badge_info
+            && self.reply == other.reply
+            && self.source == other.source
+            && self.tags == other.tags
+    }
+}
+
 impl IrcMessage {
     pub fn new(
         nick: String,
@@ -36,25 +141,836 @@ impl IrcMessage {
         action: bool,
         tags: HashMap<String, String>,
     ) -> Self {
+        let badges = tags.get("badges").map(|b| Badge::parse_list(b)).unwrap_or_default();
+        let badge_info = tags.get("badge-info").map(|b| BadgeInfo::parse_list(b)).unwrap_or_default();
+        let reply = ReplyContext::from_tags(&tags);
+        let user = ChatUser::from_tags(&nick, &tags);
+        let source = SharedChatSource::from_tags(&tags);
+        let received = Local::now();
+
         Self {
-            timestamp: Local::now(),
+            timestamp: sent_timestamp(&tags).unwrap_or(received),
+            received,
             nick,
+            user,
             channel,
             message,
             action,
+            badges,
+            badge_info,
+            reply,
+            source,
             tags,
         }
     }
 
-    pub fn timestamp(&self) -> String {
-        self.timestamp.format("%H:%M:%S").to_string()
+    pub fn has_badge(&self, name: &str) -> bool {
+        self.badges.iter().any(|b| b.name == name)
+    }
+
+    pub fn is_broadcaster(&self) -> bool {
+        self.has_badge("broadcaster")
+    }
+
+    pub fn is_moderator(&self) -> bool {
+        self.has_badge("moderator") || self.user.moderator
+    }
+
+    pub fn is_vip(&self) -> bool {
+        self.has_badge("vip") || self.tags.contains_key("vip")
+    }
+
+    /// Founders are subscribers too, but get the founder badge instead
+    pub fn is_subscriber(&self) -> bool {
+        self.has_badge("subscriber") || self.has_badge("founder") || self.user.subscriber
+    }
+
+    /// Id of the channel the message was sent in.
+    /// In a Shared Chat session this can be another channel than the one it was received in.
+    pub fn origin_room_id(&self) -> Option<&str> {
+        match &self.source {
+            Some(source) => Some(&source.room_id),
+            None => self.tags.get("room-id").map(String::as_str),
+        }
+    }
+
+    /// True if the message was sent in another channel of a Shared Chat session
+    pub fn is_from_other_room(&self) -> bool {
+        match &self.source {
+            Some(source) => self.tags.get("room-id") != Some(&source.room_id),
+            None => false,
+        }
+    }
+
+    /// The message without the leading `@login` Twitch adds to replies.
+    /// Returns the message as is if it's not a reply.
+    pub fn message_without_reply_mention(&self) -> &str {
+        let login = match &self.reply {
+            Some(reply) => &reply.parent_user_login,
+            None => return &self.message,
+        };
+
+        let rest = self.message.strip_prefix('@').and_then(|msg| {
+            let name = msg.get(..login.len())?;
+            let rest = &msg[login.len()..];
+            match name.eq_ignore_ascii_case(login) && (rest.is_empty() || rest.starts_with(' ')) {
+                true => Some(rest.trim_start_matches(' ')),
+                false => None,
+            }
+        });
+
+        rest.unwrap_or(&self.message)
+    }
+
+    /// Emote positions from the `emotes` tag
+    pub fn emotes(&self) -> Vec<EmoteRange> {
+        self.tags.get("emotes").map(|e| EmoteRange::parse_list(e)).unwrap_or_default()
+    }
+
+    /// Split the message into text, emotes, cheermotes, mentions and links
+    pub fn fragments(&self) -> Vec<Fragment> {
+        let has_bits = self.tags.contains_key("bits");
+        fragments::fragments(&self.message, &self.emotes(), has_bits)
+    }
+
+    /// Same as `fragments` but with the byte range of each fragment in the message
+    pub fn spanned_fragments(&self) -> Vec<(std::ops::Range<usize>, Fragment)> {
+        let has_bits = self.tags.contains_key("bits");
+        fragments::spanned_fragments(&self.message, &self.emotes(), has_bits)
+    }
+
+    /// All `@login` mentions in the message.
+    /// Mentions inside emotes are not included.
+    pub fn mentions(&self) -> Vec<Mention> {
+        self.spanned_fragments()
+            .into_iter()
+            .filter_map(|(span, fragment)| match fragment {
+                Fragment::Mention { login } => Some(Mention { login, span }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Mentions of any of the given logins (e.g the bot or the broadcaster).
+    /// Logins are compared without case.
+    pub fn mentions_of(&self, logins: &[&str]) -> Vec<Mention> {
+        self.mentions()
+            .into_iter()
+            .filter(|mention| logins.iter().any(|login| login.eq_ignore_ascii_case(&mention.login)))
+            .collect()
+    }
+
+    /// Links in the message, with or without a scheme
+    pub fn links(&self) -> Vec<Link> {
+        self.spanned_fragments()
+            .into_iter()
+            .filter_map(|(span, fragment)| match fragment {
+                Fragment::Link { url } => Some(Link { url, span }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Number of bits cheered with the message
+    pub fn bits(&self) -> Option<usize> {
+        self.tags.get("bits")?.parse().ok()
+    }
+
+    /// Cheermotes in the message, e.g `Cheer100`.
+    /// Always empty if the message didn't contain bits.
+    pub fn cheermotes(&self) -> Vec<Cheermote> {
+        self.fragments()
+            .into_iter()
+            .filter_map(|fragment| match fragment {
+                Fragment::Cheermote(cheermote) => Some(cheermote),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Number of months the user has subscribed, from the badge info
+    pub fn subscribed_months(&self) -> Option<usize> {
+        self.badge_info
+            .iter()
+            .find(|b| b.name == "subscriber" || b.name == "founder")
+            .and_then(|b| b.info.parse().ok())
+    }
+
+    /// Format the timestamp in the given time zone,
+    /// e.g `msg.timestamp(DEFAULT_TIMESTAMP_FORMAT, &Local)`
+    pub fn timestamp<Tz: TimeZone>(&self, format: &str, tz: &Tz) -> String
+    where
+        Tz::Offset: fmt::Display,
+    {
+        self.timestamp.with_timezone(tz).format(format).to_string()
     }
 
     pub fn color(&self) -> Option<&str> {
-        self.tags.get("color").map(|s| s as &str)
+        self.user.color.as_deref()
+    }
+
+    /// The colour to show the user's name in: their own colour or
+    /// the Twitch default colour. If a background colour is given the colour
+    /// is adjusted to be readable on it.
+    pub fn display_color(&self, background: Option<&str>) -> String {
+        let color = self.user.color_or_default();
+        background.and_then(|bg| readable_color(color, bg)).unwrap_or_else(|| color.into())
+    }
+}
+
+/// Hours, minutes and seconds in 24 hour time, e.g `18:36:48`
+pub const DEFAULT_TIMESTAMP_FORMAT: &str = "%H:%M:%S";
+
+// Time Twitch received a message, from the `tmi-sent-ts` tag (unix time in milliseconds)
+fn sent_timestamp(tags: &HashMap<String, String>) -> Option<DateTime<Local>> {
+    let millis = tags.get("tmi-sent-ts")?.parse().ok()?;
+    Local.timestamp_millis_opt(millis).single()
+}
+
+// Timestamps are the same if they are equal, or if both fell back to the time they were received
+fn same_timestamp(a: (&DateTime<Local>, &DateTime<Local>), b: (&DateTime<Local>, &DateTime<Local>)) -> bool {
+    a.0 == b.0 || (a.0 == a.1 && b.0 == b.1)
+}
+
+// -----------------------------------------------------------------------------
+//     - Chat user -
+// -----------------------------------------------------------------------------
+/// The sender of a chat message
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct ChatUser {
+    /// `None` if the message had no tags
+    pub id: Option<String>,
+    pub login: String,
+    /// Falls back to the login if the user has no display name
+    pub display_name: String,
+    /// `None` if the user never picked a colour
+    pub color: Option<String>,
+    pub user_type: UserType,
+    pub moderator: bool,
+    pub subscriber: bool,
+    pub turbo: bool,
+}
+
+impl ChatUser {
+    pub fn from_tags(login: &str, tags: &HashMap<String, String>) -> Self {
+        let flag = |key: &str| tags.get(key).map(|v| v == "1").unwrap_or(false);
+
+        let display_name = match tags.get("display-name").map(|n| n.trim()) {
+            Some(name) if !name.is_empty() => name.into(),
+            _ => login.into(),
+        };
+
+        Self {
+            id: tags.get("user-id").filter(|id| !id.is_empty()).cloned(),
+            login: login.into(),
+            display_name,
+            color: tags.get("color").filter(|c| !c.is_empty()).cloned(),
+            user_type: tags.get("user-type").map(|t| UserType::from_tag(t)).unwrap_or(UserType::Normal),
+            moderator: flag("mod"),
+            subscriber: flag("subscriber"),
+            turbo: flag("turbo"),
+        }
+    }
+
+    /// The user's colour, or the default Twitch colour for the login
+    pub fn color_or_default(&self) -> &str {
+        self.color.as_deref().unwrap_or_else(|| default_color(&self.login))
+    }
+
+    /// True if the display name is not just the login with different casing,
+    /// e.g a display name in Japanese or Korean
+    pub fn has_localized_name(&self) -> bool {
+        !self.display_name.eq_ignore_ascii_case(&self.login)
+    }
+
+    /// The name the way Twitch shows it: the display name, followed by
+    /// the login in parentheses if the display name is localized.
+    pub fn name(&self) -> String {
+        match self.has_localized_name() {
+            true => format!("{} ({})", self.display_name, self.login),
+            false => self.display_name.clone(),
+        }
+    }
+}
+
+/// The `user-type` tag
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UserType {
+    Normal,
+    Mod,
+    GlobalMod,
+    Admin,
+    Staff,
+}
+
+impl UserType {
+    pub fn from_tag(user_type: &str) -> Self {
+        match user_type {
+            "mod" => Self::Mod,
+            "global_mod" => Self::GlobalMod,
+            "admin" => Self::Admin,
+            "staff" => Self::Staff,
+            _ => Self::Normal,
+        }
+    }
+
+    /// The value of the `user-type` tag
+    pub fn to_tag(&self) -> &'static str {
+        match self {
+            Self::Normal => "",
+            Self::Mod => "mod",
+            Self::GlobalMod => "global_mod",
+            Self::Admin => "admin",
+            Self::Staff => "staff",
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Shared chat -
+// -----------------------------------------------------------------------------
+/// Where a message in a Shared Chat session was originally sent.
+/// The same message is delivered to every channel in the session.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct SharedChatSource {
+    /// Id of the channel the message was sent in
+    pub room_id: String,
+    /// Id of the message in that channel, the same in every channel
+    pub message_id: String,
+    /// The badges of the sender in that channel
+    pub badges: Vec<Badge>,
+    pub badge_info: Vec<BadgeInfo>,
+}
+
+impl SharedChatSource {
+    /// Source from the `source-*` tags
+    pub fn from_tags(tags: &HashMap<String, String>) -> Option<Self> {
+        let inst = Self {
+            room_id: tags.get("source-room-id").filter(|id| !id.is_empty())?.clone(),
+            message_id: tags.get("source-id")?.clone(),
+            badges: tags.get("source-badges").map(|b| Badge::parse_list(b)).unwrap_or_default(),
+            badge_info: tags.get("source-badge-info").map(|b| BadgeInfo::parse_list(b)).unwrap_or_default(),
+        };
+
+        Some(inst)
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Reply -
+// -----------------------------------------------------------------------------
+/// The message a reply was made to
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct ReplyContext {
+    pub parent_msg_id: String,
+    pub parent_user_id: Option<String>,
+    pub parent_user_login: String,
+    pub parent_display_name: String,
+    pub parent_msg_body: String,
+    /// The first message in the thread, if this is a reply to a reply
+    pub thread_parent_msg_id: Option<String>,
+    pub thread_parent_user_login: Option<String>,
+}
+
+impl ReplyContext {
+    /// Reply context from the `reply-parent-*` and `reply-thread-parent-*` tags
+    pub fn from_tags(tags: &HashMap<String, String>) -> Option<Self> {
+        let inst = Self {
+            parent_msg_id: tags.get("reply-parent-msg-id")?.clone(),
+            parent_user_id: tags.get("reply-parent-user-id").cloned(),
+            parent_user_login: tags.get("reply-parent-user-login")?.clone(),
+            parent_display_name: tags.get("reply-parent-display-name").cloned().unwrap_or_default(),
+            parent_msg_body: tags.get("reply-parent-msg-body").cloned().unwrap_or_default(),
+            thread_parent_msg_id: tags.get("reply-thread-parent-msg-id").cloned(),
+            thread_parent_user_login: tags.get("reply-thread-parent-user-login").cloned(),
+        };
+
+        Some(inst)
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Badges -
+// -----------------------------------------------------------------------------
+/// Chat badge, e.g `subscriber/3012` from the `badges` tag
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct Badge {
+    pub name: String,
+    pub version: String,
+}
+
+impl Badge {
+    /// Parse a comma separated list of badges, e.g `broadcaster/1,subscriber/3012`
+    pub fn parse_list(badges: &str) -> Vec<Self> {
+        split_badges(badges).map(|(name, version)| Self { name, version }).collect()
     }
 }
 
+/// Extra information about a badge from the `badge-info` tag,
+/// e.g `subscriber/17` where 17 is the number of months subscribed
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct BadgeInfo {
+    pub name: String,
+    pub info: String,
+}
+
+impl BadgeInfo {
+    /// Parse a comma separated list of badge info, e.g `subscriber/17`
+    pub fn parse_list(badge_info: &str) -> Vec<Self> {
+        split_badges(badge_info).map(|(name, info)| Self { name, info }).collect()
+    }
+}
+
+fn split_badges(badges: &str) -> impl Iterator<Item = (String, String)> + '_ {
+    badges.split(',').filter(|b| !b.is_empty()).map(|badge| {
+        let (name, version) = badge.split_once('/').unwrap_or((badge, ""));
+        (name.into(), version.into())
+    })
+}
+
+// -----------------------------------------------------------------------------
+//     - User notice -
+// -----------------------------------------------------------------------------
+/// User notice (subs, resubs, gifts, raids, announcements etc.)
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct UserNotice {
+    /// When Twitch received the notice (`tmi-sent-ts`),
+    /// or when it was received if the tag is missing
+    pub timestamp: DateTime<Local>,
+    /// When the notice was received
+    pub received: DateTime<Local>,
+    /// Login name of the user who caused the notice
+    pub login: String,
+    pub channel: String,
+    /// Message as Twitch would display it, e.g "togglebit subscribed at Tier 1."
+    pub system_msg: String,
+    /// Optional message entered by the user (e.g a resub message or announcement)
+    pub message: Option<String>,
+    pub kind: UserNoticeKind,
+    pub tags: HashMap<String, String>,
+}
+
+// Same as for `IrcMessage`: the receive time is not compared
+impl PartialEq for UserNotice {
+    fn eq(&self, other: &Self) -> bool {
+        same_timestamp((&self.timestamp, &self.received), (&other.timestamp, &other.received))
+            && self.login == other.login
+            && self.channel == other.channel
+            && self.system_msg == other.system_msg
+            && self.message == other.message
+            && self.kind == other.kind
+            && self.tags == other.tags
+    }
+}
+
+impl UserNotice {
+    pub fn new(
+        login: String,
+        channel: String,
+        system_msg: String,
+        message: Option<String>,
+        kind: UserNoticeKind,
+        tags: HashMap<String, String>,
+    ) -> Self {
+        let received = Local::now();
+
+        Self {
+            timestamp: sent_timestamp(&tags).unwrap_or(received),
+            received,
+            login,
+            channel,
+            system_msg,
+            message,
+            kind,
+            tags,
+        }
+    }
+}
+
+/// The `msg-id` of a user notice, along with the `msg-param-*` tags
+/// relevant to that kind of notice.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum UserNoticeKind {
+    Sub(SubInfo),
+    Resub(SubInfo),
+    SubGift(SubGiftInfo),
+    AnonSubGift(SubGiftInfo),
+    SubMysteryGift {
+        /// Number of subs gifted in this batch
+        mass_gift_count: usize,
+        /// Total number of subs gifted by the user in the channel, if shared
+        sender_count: Option<usize>,
+        sub_plan: SubPlan,
+    },
+    GiftPaidUpgrade {
+        sender_login: String,
+        sender_name: String,
+        promo_name: Option<String>,
+        promo_gift_total: Option<usize>,
+    },
+    AnonGiftPaidUpgrade {
+        promo_name: Option<String>,
+        promo_gift_total: Option<usize>,
+    },
+    PrimePaidUpgrade {
+        sub_plan: SubPlan,
+    },
+    Raid {
+        /// Display name of the raiding broadcaster
+        display_name: String,
+        /// Login name of the raiding broadcaster
+        login: String,
+        viewer_count: usize,
+    },
+    Unraid,
+    Ritual {
+        /// Name of the ritual, e.g "new_chatter"
+        name: String,
+    },
+    BitsBadgeTier {
+        /// The tier of the bits badge the user earned, e.g 1000, 10000
+        threshold: usize,
+    },
+    Announcement {
+        /// Highlight colour of the announcement, e.g "PRIMARY" or "BLUE"
+        color: String,
+    },
+    /// Any `msg-id` not covered above, or a notice missing required params
+    Unknown {
+        msg_id: String,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct SubInfo {
+    /// Cumulative number of months the user has subscribed
+    pub cumulative_months: usize,
+    /// Consecutive months subscribed, only present if the user chose to share it
+    pub streak_months: Option<usize>,
+    pub sub_plan: SubPlan,
+    pub sub_plan_name: String,
+    /// Number of months purchased as part of a multi-month subscription
+    pub multimonth_duration: Option<usize>,
+    pub was_gifted: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct SubGiftInfo {
+    /// Total number of months the recipient has subscribed
+    pub months: usize,
+    pub recipient_id: String,
+    pub recipient_login: String,
+    pub recipient_display_name: String,
+    pub sub_plan: SubPlan,
+    pub sub_plan_name: String,
+    /// Number of months gifted as part of a multi-month gift
+    pub gift_months: Option<usize>,
+}
+
+/// Subscription plan
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum SubPlan {
+    Prime,
+    Tier1,
+    Tier2,
+    Tier3,
+}
+
+impl SubPlan {
+    /// Sub plan from the id used by Twitch: Prime, 1000, 2000 or 3000
+    pub fn from_id(id: &str) -> Option<Self> {
+        match id {
+            "Prime" => Some(Self::Prime),
+            "1000" => Some(Self::Tier1),
+            "2000" => Some(Self::Tier2),
+            "3000" => Some(Self::Tier3),
+            _ => None,
+        }
+    }
+
+    /// The id used by Twitch for the sub plan
+    pub fn id(&self) -> &'static str {
+        match self {
+            Self::Prime => "Prime",
+            Self::Tier1 => "1000",
+            Self::Tier2 => "2000",
+            Self::Tier3 => "3000",
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Room state -
+// -----------------------------------------------------------------------------
+/// Chat room settings.
+/// Twitch sends the full state when joining a channel, and after that
+/// only the settings that changed, so any field can be `None`.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct RoomState {
+    pub channel: String,
+    pub room_id: Option<String>,
+    pub emote_only: Option<bool>,
+    /// -1 if followers only is off, otherwise the number of minutes
+    /// a user has to follow before they can chat
+    pub followers_only: Option<i64>,
+    /// Unique chat mode
+    pub r9k: Option<bool>,
+    /// Number of seconds a user has to wait between messages, 0 if off
+    pub slow: Option<u64>,
+    pub subs_only: Option<bool>,
+}
+
+impl RoomState {
+    /// Apply a (possibly partial) room state on top of this one
+    pub fn merge(&mut self, update: RoomState) {
+        self.room_id = update.room_id.or_else(|| self.room_id.take());
+        self.emote_only = update.emote_only.or(self.emote_only);
+        self.followers_only = update.followers_only.or(self.followers_only);
+        self.r9k = update.r9k.or(self.r9k);
+        self.slow = update.slow.or(self.slow);
+        self.subs_only = update.subs_only.or(self.subs_only);
+    }
+
+    pub fn is_followers_only(&self) -> bool {
+        matches!(self.followers_only, Some(minutes) if minutes >= 0)
+    }
+
+    pub fn is_slow(&self) -> bool {
+        matches!(self.slow, Some(secs) if secs > 0)
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Notice -
+// -----------------------------------------------------------------------------
+/// Notice from Twitch, e.g a mode change or a message being rejected
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Notice {
+    /// `None` if the notice isn't for a specific channel (e.g a failed login)
+    pub channel: Option<String>,
+    pub kind: NoticeKind,
+    pub message: String,
+}
+
+/// The `msg-id` of a notice
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NoticeKind {
+    /// The login failed, most likely because of a bad token.
+    /// Twitch doesn't send a `msg-id` with this one.
+    LoginFailed,
+    EmoteOnlyOn,
+    EmoteOnlyOff,
+    FollowersOn,
+    FollowersOnZero,
+    FollowersOff,
+    R9kOn,
+    R9kOff,
+    SlowOn,
+    SlowOff,
+    SubsOn,
+    SubsOff,
+    MsgBanned,
+    MsgChannelSuspended,
+    MsgDuplicate,
+    MsgEmoteonly,
+    MsgFollowersonly,
+    MsgFollowersonlyZero,
+    MsgR9k,
+    MsgRatelimit,
+    MsgRejected,
+    MsgRequiresVerifiedPhoneNumber,
+    MsgSlowmode,
+    MsgSubsonly,
+    MsgSuspended,
+    MsgTimedout,
+    MsgVerifiedEmail,
+    /// The channel was closed for breaking the Terms of Service
+    TosBan,
+    UnrecognizedCmd,
+    /// Any `msg-id` not covered above
+    Unknown {
+        msg_id: String,
+    },
+}
+
+impl NoticeKind {
+    pub fn from_msg_id(msg_id: &str) -> Self {
+        match msg_id {
+            "emote_only_on" => Self::EmoteOnlyOn,
+            "emote_only_off" => Self::EmoteOnlyOff,
+            "followers_on" => Self::FollowersOn,
+            "followers_on_zero" => Self::FollowersOnZero,
+            "followers_off" => Self::FollowersOff,
+            "r9k_on" => Self::R9kOn,
+            "r9k_off" => Self::R9kOff,
+            "slow_on" => Self::SlowOn,
+            "slow_off" => Self::SlowOff,
+            "subs_on" => Self::SubsOn,
+            "subs_off" => Self::SubsOff,
+            "msg_banned" => Self::MsgBanned,
+            "msg_channel_suspended" => Self::MsgChannelSuspended,
+            "msg_duplicate" => Self::MsgDuplicate,
+            "msg_emoteonly" => Self::MsgEmoteonly,
+            "msg_followersonly" => Self::MsgFollowersonly,
+            "msg_followersonly_zero" => Self::MsgFollowersonlyZero,
+            "msg_r9k" => Self::MsgR9k,
+            "msg_ratelimit" => Self::MsgRatelimit,
+            "msg_rejected" => Self::MsgRejected,
+            "msg_requires_verified_phone_number" => Self::MsgRequiresVerifiedPhoneNumber,
+            "msg_slowmode" => Self::MsgSlowmode,
+            "msg_subsonly" => Self::MsgSubsonly,
+            "msg_suspended" => Self::MsgSuspended,
+            "msg_timedout" => Self::MsgTimedout,
+            "msg_verified_email" => Self::MsgVerifiedEmail,
+            "tos_ban" => Self::TosBan,
+            "unrecognized_cmd" => Self::UnrecognizedCmd,
+            _ => Self::Unknown { msg_id: msg_id.into() },
+        }
+    }
+
+    /// The `msg-id` of the notice. `None` for a failed login
+    pub fn msg_id(&self) -> Option<&str> {
+        let msg_id = match self {
+            Self::LoginFailed => return None,
+            Self::EmoteOnlyOn => "emote_only_on",
+            Self::EmoteOnlyOff => "emote_only_off",
+            Self::FollowersOn => "followers_on",
+            Self::FollowersOnZero => "followers_on_zero",
+            Self::FollowersOff => "followers_off",
+            Self::R9kOn => "r9k_on",
+            Self::R9kOff => "r9k_off",
+            Self::SlowOn => "slow_on",
+            Self::SlowOff => "slow_off",
+            Self::SubsOn => "subs_on",
+            Self::SubsOff => "subs_off",
+            Self::MsgBanned => "msg_banned",
+            Self::MsgChannelSuspended => "msg_channel_suspended",
+            Self::MsgDuplicate => "msg_duplicate",
+            Self::MsgEmoteonly => "msg_emoteonly",
+            Self::MsgFollowersonly => "msg_followersonly",
+            Self::MsgFollowersonlyZero => "msg_followersonly_zero",
+            Self::MsgR9k => "msg_r9k",
+            Self::MsgRatelimit => "msg_ratelimit",
+            Self::MsgRejected => "msg_rejected",
+            Self::MsgRequiresVerifiedPhoneNumber => "msg_requires_verified_phone_number",
+            Self::MsgSlowmode => "msg_slowmode",
+            Self::MsgSubsonly => "msg_subsonly",
+            Self::MsgSuspended => "msg_suspended",
+            Self::MsgTimedout => "msg_timedout",
+            Self::MsgVerifiedEmail => "msg_verified_email",
+            Self::TosBan => "tos_ban",
+            Self::UnrecognizedCmd => "unrecognized_cmd",
+            Self::Unknown { msg_id } if msg_id.is_empty() => return None,
+            Self::Unknown { msg_id } => msg_id,
+        };
+
+        Some(msg_id)
+    }
+
+    /// True if this notice means a message sent by the bot was not delivered
+    pub fn is_rejection(&self) -> bool {
+        matches!(
+            self,
+            Self::MsgBanned
+                | Self::MsgChannelSuspended
+                | Self::MsgDuplicate
+                | Self::MsgEmoteonly
+                | Self::MsgFollowersonly
+                | Self::MsgFollowersonlyZero
+                | Self::MsgR9k
+                | Self::MsgRatelimit
+                | Self::MsgRejected
+                | Self::MsgRequiresVerifiedPhoneNumber
+                | Self::MsgSlowmode
+                | Self::MsgSubsonly
+                | Self::MsgSuspended
+                | Self::MsgTimedout
+                | Self::MsgVerifiedEmail
+        )
+    }
+
+    /// True if this notice means the bot could not join the channel
+    pub fn is_join_failure(&self) -> bool {
+        matches!(self, Self::MsgChannelSuspended | Self::TosBan)
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - User state -
+// -----------------------------------------------------------------------------
+/// The state of the logged in user (the bot), from USERSTATE and GLOBALUSERSTATE
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct UserState {
+    /// `None` for the global user state
+    pub channel: Option<String>,
+    /// Only included in the global user state
+    pub user_id: Option<String>,
+    pub display_name: String,
+    pub color: Option<String>,
+    /// Ids of the emote sets the user can use
+    pub emote_sets: Vec<String>,
+    /// Badges of the bot in the channel
+    pub badges: Vec<Badge>,
+    /// Moderator in the channel
+    pub moderator: bool,
+    pub tags: HashMap<String, String>,
+}
+
+impl UserState {
+    pub fn has_badge(&self, name: &str) -> bool {
+        self.badges.iter().any(|b| b.name == name)
+    }
+
+    /// True if the bot is the broadcaster of the channel
+    pub fn is_broadcaster(&self) -> bool {
+        self.has_badge("broadcaster")
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Say -
+//     Commands sent to the chat agent by clients
+// -----------------------------------------------------------------------------
+/// Send a message to a channel, e.g `say {"channel":"togglebit","text":"hello"}`
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Say {
+    /// Channel name, with or without the `#`
+    pub channel: String,
+    pub text: String,
+    /// Id of the message to reply to
+    #[serde(default)]
+    pub reply_to: Option<String>,
+    /// Any id picked by the client, sent back in the response.
+    /// Messages to different channels can be sent out of order,
+    /// so this tells the responses to several messages apart.
+    #[serde(default)]
+    pub id: Option<String>,
+}
+
+/// Response from the chat agent to the client that sent a command.
+/// `Ok` means the line was written to the socket, not that Twitch accepted it:
+/// Twitch rejects messages (e.g slow mode or a ban) with a NOTICE,
+/// which subscribers get as an `Irc::Notice`.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub enum ChatResponse {
+    Ok {
+        command: String,
+        /// Channel the command was for, without the `#`
+        channel: Option<String>,
+        /// The id from the `say` command
+        id: Option<String>,
+    },
+    Error {
+        command: String,
+        channel: Option<String>,
+        id: Option<String>,
+        reason: String,
+    },
+}
+
 // -----------------------------------------------------------------------------
 //     - Pubsub messages -
 //     Thanks to Bare!
@@ -56,20 +56,29 @@ async fn main() -> Result<()> {
 
     // Agents
     let chat_agent = router.new_agent(None, Address::Chat)?;
-    let cpoints_agent = router.new_agent(None, Address::ChannelPoints)?;
     let server_agent = router.new_agent(None, Address::Server)?;
 
     // Handles, so the application can close properly
     let chat_handle = tokio::spawn(chat::run(chat_agent, config));
-    let cpoints_handle = tokio::spawn(channelpoints::run(cpoints_agent, config));
     let server_handle = tokio::spawn(server::run(server_agent, "127.0.0.1:6000"));
 
+    // PubSub needs a token
+    let cpoints_handle = match config.anonymous {
+        false => {
+            let cpoints_agent = router.new_agent(None, Address::ChannelPoints)?;
+            Some(tokio::spawn(channelpoints::run(cpoints_agent, config)))
+        }
+        true => None,
+    };
+
     // Run the router
     router.run().await;
 
     // Wait for the handles to finish before exiting
     chat_handle.await??;
-    cpoints_handle.await??;
+    if let Some(cpoints_handle) = cpoints_handle {
+        cpoints_handle.await??;
+    }
     server_handle.await??;
 
     // ... and done
@@ -0,0 +1,469 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use chrono::{DateTime, Local};
+
+use crate::{
+    sent_timestamp, Badge, BadgeInfo, ChatUser, Irc, IrcMessage, Notice, ReplyContext, RoomState, Say,
+    SharedChatSource, SubGiftInfo, SubInfo, UserNotice, UserNoticeKind, UserState,
+};
+
+const TMI: &str = ":tmi.twitch.tv";
+
+// -----------------------------------------------------------------------------
+//     - Irc to raw -
+//     Turn parsed messages back into IRC lines.
+//     Fields Twitch sends as tags (badges, user notice kind, reply etc.)
+//     are written over the tags, so a message built or changed by hand
+//     gives the line it describes.
+// -----------------------------------------------------------------------------
+impl Irc {
+    /// The message as an IRC line, including the trailing `\r\n`.
+    /// `Irc::Chatters` has no line of its own: it's written as the NAMES reply
+    /// it's built from, so it parses back as `Irc::Names`.
+    pub fn to_raw(&self) -> String {
+        match self {
+            Irc::ClearChat { channel, target_user, target_user_id, ban_duration } => {
+                let mut tags = HashMap::new();
+                if let Some(duration) = ban_duration {
+                    tags.insert("ban-duration".to_string(), duration.to_string());
+                }
+                if let Some(id) = target_user_id {
+                    tags.insert("target-user-id".to_string(), id.clone());
+                }
+                line(&tags, TMI, "CLEARCHAT", &format!("#{}", channel), target_user.as_deref())
+            }
+            Irc::ClearMessage { channel, login, target_msg_id } => {
+                let tags = HashMap::from([
+                    ("login".to_string(), login.clone()),
+                    ("target-msg-id".to_string(), target_msg_id.clone()),
+                ]);
+                line(&tags, TMI, "CLEARMSG", &format!("#{}", channel), None)
+            }
+            Irc::Message(msg) => msg.to_irc_line(),
+            Irc::UserNotice(notice) => notice.to_irc_line(),
+            Irc::RoomState(state) => state.to_irc_line(),
+            Irc::Notice(notice) => notice.to_irc_line(),
+            Irc::UserState(state) => state.to_irc_line("USERSTATE"),
+            Irc::GlobalUserState(state) => state.to_irc_line("GLOBALUSERSTATE"),
+            Irc::Join { channel, login } => {
+                line(&HashMap::new(), &user_prefix(login), "JOIN", &format!("#{}", channel), None)
+            }
+            Irc::Part { channel, login } => {
+                line(&HashMap::new(), &user_prefix(login), "PART", &format!("#{}", channel), None)
+            }
+            Irc::Names { channel, logins } => {
+                let params = format!("* = #{}", channel);
+                line(&HashMap::new(), TMI, "353", &params, Some(&logins.join(" ")))
+            }
+            Irc::NamesEnd { channel } => {
+                let params = format!("* #{}", channel);
+                line(&HashMap::new(), TMI, "366", &params, Some("End of /NAMES list"))
+            }
+            Irc::Chatters { channel, chatters } => {
+                let params = format!("* = #{}", channel);
+                line(&HashMap::new(), TMI, "353", &params, Some(&chatters.join(" ")))
+            }
+            Irc::Reconnect => line(&HashMap::new(), TMI, "RECONNECT", "", None),
+            Irc::Raw { tags, prefix, command, params, trailing } => {
+                let prefix = match prefix.is_empty() {
+                    true => String::new(),
+                    false => format!(":{}", prefix),
+                };
+                line(tags, &prefix, command, &params.join(" "), trailing.as_deref())
+            }
+        }
+    }
+}
+
+impl IrcMessage {
+    /// The message as a PRIVMSG line, including the trailing `\r\n`
+    pub fn to_irc_line(&self) -> String {
+        let message = match self.action {
+            true => format!("\u{1}ACTION {}\u{1}", self.message),
+            false => self.message.clone(),
+        };
+
+        let params = format!("#{}", self.channel);
+        line(&self.typed_tags(), &user_prefix(&self.nick), "PRIVMSG", &params, Some(&message))
+    }
+
+    // The tags with the typed fields written over them.
+    // Fields the tags already parse to are left alone, so a parsed message keeps its tags.
+    fn typed_tags(&self) -> HashMap<String, String> {
+        let mut tags = self.tags.clone();
+
+        if ChatUser::from_tags(&self.nick, &tags) != self.user {
+            self.user.write_tags(&mut tags);
+        }
+
+        if tags.get("badges").map(|b| Badge::parse_list(b)).unwrap_or_default() != self.badges {
+            tags.insert("badges".into(), badge_list(&self.badges));
+        }
+
+        if tags.get("badge-info").map(|b| BadgeInfo::parse_list(b)).unwrap_or_default() != self.badge_info {
+            tags.insert("badge-info".into(), badge_info_list(&self.badge_info));
+        }
+
+        if ReplyContext::from_tags(&tags) != self.reply {
+            tags.retain(|key, _| !key.starts_with("reply-"));
+            if let Some(reply) = &self.reply {
+                reply.write_tags(&mut tags);
+            }
+        }
+
+        if SharedChatSource::from_tags(&tags) != self.source {
+            tags.retain(|key, _| !key.starts_with("source-"));
+            if let Some(source) = &self.source {
+                source.write_tags(&mut tags);
+            }
+        }
+
+        set_timestamp(&mut tags, &self.timestamp, &self.received);
+        tags
+    }
+}
+
+impl ChatUser {
+    fn write_tags(&self, tags: &mut HashMap<String, String>) {
+        match &self.id {
+            Some(id) => tags.insert("user-id".into(), id.clone()),
+            None => tags.remove("user-id"),
+        };
+        tags.insert("display-name".into(), self.display_name.clone());
+        tags.insert("color".into(), self.color.clone().unwrap_or_default());
+        tags.insert("user-type".into(), self.user_type.to_tag().into());
+        tags.insert("mod".into(), flag_tag(self.moderator));
+        tags.insert("subscriber".into(), flag_tag(self.subscriber));
+        tags.insert("turbo".into(), flag_tag(self.turbo));
+    }
+}
+
+impl ReplyContext {
+    fn write_tags(&self, tags: &mut HashMap<String, String>) {
+        let mut insert = |key: &str, val: Option<String>| {
+            if let Some(val) = val {
+                tags.insert(key.to_string(), val);
+            }
+        };
+        insert("reply-parent-msg-id", Some(self.parent_msg_id.clone()));
+        insert("reply-parent-user-id", self.parent_user_id.clone());
+        insert("reply-parent-user-login", Some(self.parent_user_login.clone()));
+        insert("reply-parent-display-name", Some(self.parent_display_name.clone()));
+        insert("reply-parent-msg-body", Some(self.parent_msg_body.clone()));
+        insert("reply-thread-parent-msg-id", self.thread_parent_msg_id.clone());
+        insert("reply-thread-parent-user-login", self.thread_parent_user_login.clone());
+    }
+}
+
+impl SharedChatSource {
+    fn write_tags(&self, tags: &mut HashMap<String, String>) {
+        tags.insert("source-room-id".into(), self.room_id.clone());
+        tags.insert("source-id".into(), self.message_id.clone());
+        tags.insert("source-badges".into(), badge_list(&self.badges));
+        tags.insert("source-badge-info".into(), badge_info_list(&self.badge_info));
+    }
+}
+
+impl UserNotice {
+    /// The notice as a USERNOTICE line, including the trailing `\r\n`
+    pub fn to_irc_line(&self) -> String {
+        let mut tags = self.tags.clone();
+        tags.insert("login".into(), self.login.clone());
+        tags.insert("system-msg".into(), self.system_msg.clone());
+        tags.extend(self.kind.to_tags());
+        set_timestamp(&mut tags, &self.timestamp, &self.received);
+
+        let params = format!("#{}", self.channel);
+        line(&tags, TMI, "USERNOTICE", &params, self.message.as_deref())
+    }
+}
+
+impl UserNoticeKind {
+    /// The `msg-id` and `msg-param-*` tags of the notice
+    pub fn to_tags(&self) -> HashMap<String, String> {
+        let mut tags = HashMap::new();
+        let mut param = |name: &str, val: Option<String>| {
+            if let Some(val) = val {
+                tags.insert(format!("msg-param-{}", name), val);
+            }
+        };
+
+        let msg_id = match self {
+            Self::Sub(info) => {
+                info.write_params(&mut param);
+                "sub"
+            }
+            Self::Resub(info) => {
+                info.write_params(&mut param);
+                "resub"
+            }
+            Self::SubGift(info) => {
+                info.write_params(&mut param);
+                "subgift"
+            }
+            Self::AnonSubGift(info) => {
+                info.write_params(&mut param);
+                "anonsubgift"
+            }
+            Self::SubMysteryGift { mass_gift_count, sender_count, sub_plan } => {
+                param("mass-gift-count", Some(mass_gift_count.to_string()));
+                param("sender-count", sender_count.map(|c| c.to_string()));
+                param("sub-plan", Some(sub_plan.id().into()));
+                "submysterygift"
+            }
+            Self::GiftPaidUpgrade { sender_login, sender_name, promo_name, promo_gift_total } => {
+                param("sender-login", Some(sender_login.clone()));
+                param("sender-name", Some(sender_name.clone()));
+                param("promo-name", promo_name.clone());
+                param("promo-gift-total", promo_gift_total.map(|t| t.to_string()));
+                "giftpaidupgrade"
+            }
+            Self::AnonGiftPaidUpgrade { promo_name, promo_gift_total } => {
+                param("promo-name", promo_name.clone());
+                param("promo-gift-total", promo_gift_total.map(|t| t.to_string()));
+                "anongiftpaidupgrade"
+            }
+            Self::PrimePaidUpgrade { sub_plan } => {
+                param("sub-plan", Some(sub_plan.id().into()));
+                "primepaidupgrade"
+            }
+            Self::Raid { display_name, login, viewer_count } => {
+                param("displayName", Some(display_name.clone()));
+                param("login", Some(login.clone()));
+                param("viewerCount", Some(viewer_count.to_string()));
+                "raid"
+            }
+            Self::Unraid => "unraid",
+            Self::Ritual { name } => {
+                param("ritual-name", Some(name.clone()));
+                "ritual"
+            }
+            Self::BitsBadgeTier { threshold } => {
+                param("threshold", Some(threshold.to_string()));
+                "bitsbadgetier"
+            }
+            Self::Announcement { color } => {
+                param("color", Some(color.clone()));
+                "announcement"
+            }
+            Self::Unknown { msg_id } => msg_id,
+        };
+
+        tags.insert("msg-id".into(), msg_id.into());
+        tags
+    }
+}
+
+impl SubInfo {
+    fn write_params(&self, param: &mut impl FnMut(&str, Option<String>)) {
+        param("cumulative-months", Some(self.cumulative_months.to_string()));
+        // The streak is only read if the user chose to share it
+        param("should-share-streak", Some(flag_tag(self.streak_months.is_some())));
+        param("streak-months", self.streak_months.map(|m| m.to_string()));
+        param("sub-plan", Some(self.sub_plan.id().into()));
+        param("sub-plan-name", Some(self.sub_plan_name.clone()));
+        param("multimonth-duration", self.multimonth_duration.map(|d| d.to_string()));
+        param("was-gifted", Some(self.was_gifted.to_string()));
+    }
+}
+
+impl SubGiftInfo {
+    fn write_params(&self, param: &mut impl FnMut(&str, Option<String>)) {
+        param("months", Some(self.months.to_string()));
+        param("recipient-id", Some(self.recipient_id.clone()));
+        param("recipient-user-name", Some(self.recipient_login.clone()));
+        param("recipient-display-name", Some(self.recipient_display_name.clone()));
+        param("sub-plan", Some(self.sub_plan.id().into()));
+        param("sub-plan-name", Some(self.sub_plan_name.clone()));
+        param("gift-months", self.gift_months.map(|m| m.to_string()));
+    }
+}
+
+impl RoomState {
+    /// The room state as a ROOMSTATE line, including the trailing `\r\n`
+    pub fn to_irc_line(&self) -> String {
+        let flag = |flag: bool| if flag { "1" } else { "0" }.to_string();
+
+        let mut tags = HashMap::new();
+        let mut insert = |key: &str, val: Option<String>| {
+            if let Some(val) = val {
+                tags.insert(key.to_string(), val);
+            }
+        };
+        insert("room-id", self.room_id.clone());
+        insert("emote-only", self.emote_only.map(flag));
+        insert("followers-only", self.followers_only.map(|f| f.to_string()));
+        insert("r9k", self.r9k.map(flag));
+        insert("slow", self.slow.map(|s| s.to_string()));
+        insert("subs-only", self.subs_only.map(flag));
+
+        line(&tags, TMI, "ROOMSTATE", &format!("#{}", self.channel), None)
+    }
+}
+
+impl Notice {
+    /// The notice as a NOTICE line, including the trailing `\r\n`
+    pub fn to_irc_line(&self) -> String {
+        let mut tags = HashMap::new();
+        if let Some(msg_id) = self.kind.msg_id() {
+            tags.insert("msg-id".to_string(), msg_id.to_string());
+        }
+
+        let params = match &self.channel {
+            Some(channel) => format!("#{}", channel),
+            None => "*".to_string(),
+        };
+
+        line(&tags, TMI, "NOTICE", &params, Some(&self.message))
+    }
+}
+
+impl UserState {
+    fn to_irc_line(&self, command: &str) -> String {
+        let params = match &self.channel {
+            Some(channel) => format!("#{}", channel),
+            None => String::new(),
+        };
+
+        line(&self.typed_tags(), TMI, command, &params, None)
+    }
+
+    // Same as for `IrcMessage`: only fields that differ from the tags are written
+    fn typed_tags(&self) -> HashMap<String, String> {
+        let mut tags = self.tags.clone();
+
+        if tags.get("user-id") != self.user_id.as_ref() {
+            match &self.user_id {
+                Some(id) => tags.insert("user-id".into(), id.clone()),
+                None => tags.remove("user-id"),
+            };
+        }
+
+        if tags.get("display-name").map(String::as_str).unwrap_or_default() != self.display_name {
+            tags.insert("display-name".into(), self.display_name.clone());
+        }
+
+        if tags.get("color").filter(|c| !c.is_empty()) != self.color.as_ref() {
+            tags.insert("color".into(), self.color.clone().unwrap_or_default());
+        }
+
+        let emote_sets = self.emote_sets.join(",");
+        if tags.get("emote-sets").map(String::as_str).unwrap_or_default() != emote_sets {
+            tags.insert("emote-sets".into(), emote_sets);
+        }
+
+        if tags.get("badges").map(|b| Badge::parse_list(b)).unwrap_or_default() != self.badges {
+            tags.insert("badges".into(), badge_list(&self.badges));
+        }
+
+        if (tags.get("mod").map(String::as_str) == Some("1")) != self.moderator {
+            tags.insert("mod".into(), flag_tag(self.moderator));
+        }
+
+        tags
+    }
+}
+
+impl Say {
+    /// The message as a PRIVMSG line to send to Twitch, including the trailing `\r\n`.
+    /// Replies are sent with the `reply-parent-msg-id` tag.
+    /// Whitespace is removed from the channel and line breaks in the text are replaced
+    /// with spaces, so neither can end the line early.
+    pub fn to_irc_line(&self) -> String {
+        let mut tags = HashMap::new();
+        if let Some(id) = &self.reply_to {
+            tags.insert("reply-parent-msg-id".to_string(), id.clone());
+        }
+
+        let channel = self.channel.trim_start_matches('#').chars().filter(|c| !c.is_whitespace()).collect::<String>();
+        let text = self.text.replace(['\r', '\n'], " ");
+        line(&tags, "", "PRIVMSG", &format!("#{}", channel), Some(&text))
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Tags -
+// -----------------------------------------------------------------------------
+// A timestamp that fell back to the time the message was received has no tag
+fn set_timestamp(tags: &mut HashMap<String, String>, timestamp: &DateTime<Local>, received: &DateTime<Local>) {
+    if timestamp != received && sent_timestamp(tags).as_ref() != Some(timestamp) {
+        tags.insert("tmi-sent-ts".into(), timestamp.timestamp_millis().to_string());
+    }
+}
+
+fn flag_tag(flag: bool) -> String {
+    if flag { "1" } else { "0" }.into()
+}
+
+fn badge_list(badges: &[Badge]) -> String {
+    badges.iter().map(|b| format!("{}/{}", b.name, b.version)).collect::<Vec<_>>().join(",")
+}
+
+fn badge_info_list(badge_info: &[BadgeInfo]) -> String {
+    badge_info.iter().map(|b| format!("{}/{}", b.name, b.info)).collect::<Vec<_>>().join(",")
+}
+
+// -----------------------------------------------------------------------------
+//     - Line -
+// -----------------------------------------------------------------------------
+fn user_prefix(login: &str) -> String {
+    format!(":{0}!{0}@{0}.tmi.twitch.tv", login)
+}
+
+fn line(tags: &HashMap<String, String>, prefix: &str, command: &str, params: &str, trailing: Option<&str>) -> String {
+    let mut line = String::new();
+
+    // Sort the tags so the same message always gives the same line
+    if !tags.is_empty() {
+        let mut tags = tags.iter().collect::<Vec<_>>();
+        tags.sort();
+
+        line.push('@');
+        for (i, (key, val)) in tags.into_iter().enumerate() {
+            if i > 0 {
+                line.push(';');
+            }
+            let _ = write!(line, "{}={}", key, escape_tag_value(val));
+        }
+        line.push(' ');
+    }
+
+    if !prefix.is_empty() {
+        line.push_str(prefix);
+        line.push(' ');
+    }
+    line.push_str(command);
+
+    if !params.is_empty() {
+        line.push(' ');
+        line.push_str(params);
+    }
+
+    if let Some(trailing) = trailing {
+        line.push_str(" :");
+        line.push_str(trailing);
+    }
+
+    line.push_str("\r\n");
+    line
+}
+
+// Escape a tag value according to the IRCv3 spec:
+// https://ircv3.net/specs/extensions/message-tags#escaping-values
+fn escape_tag_value(val: &str) -> String {
+    let mut escaped = String::with_capacity(val.len());
+
+    for c in val.chars() {
+        match c {
+            ';' => escaped.push_str("\\:"),
+            ' ' => escaped.push_str("\\s"),
+            '\\' => escaped.push_str("\\\\"),
+            '\r' => escaped.push_str("\\r"),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}